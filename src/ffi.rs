@@ -0,0 +1,117 @@
+//! C bindings, built with the `ffi` feature. Every function returns one of the
+//! `CRYPTARITHM_*` codes instead of panicking across the boundary.
+//!
+//! The crate itself only builds an rlib; for a library to link against, run
+//! `cargo rustc --lib --release --features ffi --crate-type cdylib` (or `staticlib`).
+
+use crate::{Equation, SolveError};
+use itertools::Itertools;
+use std::ffi::{c_char, c_int, CStr};
+use std::panic::{self, UnwindSafe};
+use std::ptr;
+
+pub const CRYPTARITHM_OK: c_int = 0;
+/// The search finished without finding a solution.
+pub const CRYPTARITHM_NO_SOLUTION: c_int = 1;
+pub const CRYPTARITHM_NULL_POINTER: c_int = -1;
+/// The puzzle isn't valid UTF-8 or `parse_input` rejected it.
+pub const CRYPTARITHM_PARSE_ERROR: c_int = -2;
+pub const CRYPTARITHM_TIMEOUT: c_int = -3;
+/// More distinct letters than the base has digits.
+pub const CRYPTARITHM_TOO_MANY_LETTERS: c_int = -4;
+/// The solution plus its NUL doesn't fit in the caller's buffer.
+pub const CRYPTARITHM_BUFFER_TOO_SMALL: c_int = -5;
+/// A bug inside the library; the call had no effect.
+pub const CRYPTARITHM_PANIC: c_int = -6;
+
+/// A parsed puzzle, owned by the library until passed to `cryptarithm_free`.
+pub struct CryptarithmEquation(Equation);
+
+fn guard(body: impl FnOnce() -> c_int + UnwindSafe) -> c_int {
+    panic::catch_unwind(body).unwrap_or(CRYPTARITHM_PANIC)
+}
+
+/// Parses the NUL-terminated `puzzle` and stores the result in `*out`, which must later
+/// go to `cryptarithm_free`. `*out` is set to null on failure.
+///
+/// # Safety
+///
+/// `puzzle` must be null or point to a NUL-terminated string, and `out` must be null or
+/// valid for writing a pointer.
+#[no_mangle]
+pub unsafe extern "C" fn cryptarithm_parse(
+    puzzle: *const c_char,
+    out: *mut *mut CryptarithmEquation,
+) -> c_int {
+    if puzzle.is_null() || out.is_null() {
+        return CRYPTARITHM_NULL_POINTER;
+    }
+    *out = ptr::null_mut();
+    let puzzle = CStr::from_ptr(puzzle);
+    guard(|| {
+        let Ok(equation) = puzzle.to_str().map(crate::parse_input) else {
+            return CRYPTARITHM_PARSE_ERROR;
+        };
+        match equation {
+            Ok(equation) => {
+                *out = Box::into_raw(Box::new(CryptarithmEquation(equation)));
+                CRYPTARITHM_OK
+            }
+            Err(_) => CRYPTARITHM_PARSE_ERROR,
+        }
+    })
+}
+
+/// Solves `equation` and writes the solution to `buffer` as a NUL-terminated
+/// `LETTER=DIGIT;LETTER=DIGIT` list in letter order, e.g. `D=7;E=5;M=1`. Digits are
+/// written in decimal whatever the base. `buffer` is left alone unless this returns
+/// `CRYPTARITHM_OK`.
+///
+/// # Safety
+///
+/// `equation` must be null or come from `cryptarithm_parse` and not be freed yet, and
+/// `buffer` must be null or valid for writing `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn cryptarithm_solve(
+    equation: *const CryptarithmEquation,
+    buffer: *mut c_char,
+    len: usize,
+) -> c_int {
+    if equation.is_null() || buffer.is_null() {
+        return CRYPTARITHM_NULL_POINTER;
+    }
+    let equation = &(*equation).0;
+    guard(|| {
+        let mapping = match crate::solve_ref(equation) {
+            Ok(Some(mapping)) => mapping,
+            Ok(None) => return CRYPTARITHM_NO_SOLUTION,
+            // Nothing cancels or budgets this search, so either would also mean it stopped early
+            Err(SolveError::Timeout | SolveError::Cancelled | SolveError::BudgetExhausted) => {
+                return CRYPTARITHM_TIMEOUT
+            }
+            Err(SolveError::TooManyLetters { .. }) => return CRYPTARITHM_TOO_MANY_LETTERS,
+        };
+        let solution = mapping
+            .iter()
+            .map(|(ch, digit)| format!("{}={}", ch, digit))
+            .join(";");
+        if solution.len() >= len {
+            return CRYPTARITHM_BUFFER_TOO_SMALL;
+        }
+        ptr::copy_nonoverlapping(solution.as_ptr(), buffer.cast(), solution.len());
+        *buffer.add(solution.len()) = 0;
+        CRYPTARITHM_OK
+    })
+}
+
+/// Releases an equation from `cryptarithm_parse`. Null is ignored.
+///
+/// # Safety
+///
+/// `equation` must be null or come from `cryptarithm_parse`, and must not be used again.
+#[no_mangle]
+pub unsafe extern "C" fn cryptarithm_free(equation: *mut CryptarithmEquation) {
+    if !equation.is_null() {
+        drop(Box::from_raw(equation));
+    }
+}
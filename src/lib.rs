@@ -1,185 +1,3652 @@
-use cached::proc_macro::cached;
-use crossbeam_channel::Sender;
+#[cfg(all(feature = "cache", feature = "threads"))]
+use cached::{proc_macro::cached, Cached, TimedSizedCache};
+#[cfg(feature = "threads")]
+use crossbeam_channel::{Receiver, RecvTimeoutError};
 use itertools::Itertools;
+#[cfg(feature = "threads")]
+use rayon::prelude::*;
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::btree_map::Entry;
 use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+#[cfg(feature = "threads")]
+use std::io::BufRead;
+#[cfg(feature = "threads")]
+use std::iter;
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+#[cfg(all(feature = "cache", feature = "threads"))]
+use std::sync::{Mutex, MutexGuard};
+#[cfg(feature = "threads")]
 use std::thread;
 use std::time::Duration;
+use std::time::Instant;
+
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "python")]
+mod python;
+
+// Tracing goes through the `log` facade when the `log` feature is on. Without it these
+// still type-check their arguments but compile to nothing.
+#[cfg(feature = "log")]
+use log::{debug, trace};
+#[cfg(not(feature = "log"))]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if false {
+            let _ = format_args!($($arg)*);
+        }
+    };
+}
+#[cfg(not(feature = "log"))]
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        if false {
+            let _ = format_args!($($arg)*);
+        }
+    };
+}
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(into = "String", try_from = "String")
+)]
 enum Token {
     Word(Vec<char>),
     Operator(char),
+    // Unary minus
+    Negate,
+    // Integer literal, always written in decimal
+    Number(u64),
+    // Postfix `!`
+    Factorial,
+}
+
+#[cfg(feature = "serde")]
+impl From<Token> for String {
+    fn from(token: Token) -> Self {
+        match token {
+            Token::Word(chars) => chars.into_iter().collect(),
+            Token::Operator(op) => op.to_string(),
+            Token::Negate => NEGATE.to_string(),
+            Token::Number(value) => value.to_string(),
+            Token::Factorial => FACTORIAL.to_string(),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<String> for Token {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.chars().collect_vec()[..] {
+            [op] if is_operator(op) => Ok(Token::Operator(op)),
+            [NEGATE] => Ok(Token::Negate),
+            [FACTORIAL] => Ok(Token::Factorial),
+            ref chars if !chars.is_empty() && chars.iter().all(|c| c.is_ascii_digit()) => value
+                .parse()
+                .map(Token::Number)
+                .map_err(|_| format!("Invalid number {}", value)),
+            ref chars
+                if chars.iter().any(|c| c.is_alphabetic())
+                    && chars
+                        .iter()
+                        .all(|c| c.is_alphabetic() || c.is_ascii_digit()) =>
+            {
+                Ok(Token::Word(chars.to_vec()))
+            }
+            _ => Err(format!("Invalid token {}", value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum CryptarithmError {
+    /// `position` counts chars from the start of the input, or of the word for
+    /// `EquationBuilder`, starting at 0.
+    #[error("Invalid token {ch} at position {position}")]
+    InvalidToken { ch: char, position: usize },
+    #[error("Invalid operator: {0}")]
+    InvalidOperator(char),
+    #[error("Invalid RPN expresion")]
+    InvalidRpn,
+    #[error("Invalid expression: {0}")]
+    InvalidExpression(String),
+    #[error("Unbalanced parentheses")]
+    UnbalancedParens,
+    #[error("Missing = and the result after it")]
+    MissingResult,
+    /// `position` counts chars from the start of the input, starting at 0.
+    #[error("Operator {op} at position {position} is missing an operand")]
+    MisplacedOperator { op: char, position: usize },
+    #[error("Unsupported base {0}")]
+    UnsupportedBase(u8),
+    #[error("{letters} distinct letters cannot fit in base {base}")]
+    TooManyLetters { letters: usize, base: u8 },
+    #[error("Digit {digit} for letter {letter} is out of range for base {base}")]
+    DigitOutOfRange { letter: char, digit: u8, base: u8 },
+    #[error("Letter {0} does not appear in the equation")]
+    UnknownLetter(char),
+    #[error("Letter {0} is missing from the mapping")]
+    MissingLetter(char),
+    #[error("Leading letter {0} cannot be 0")]
+    LeadingZero(char),
+    #[error("Letters {0} and {1} cannot both be {2}")]
+    DuplicateDigit(char, char, u8),
+    #[error("Word {0} is too large")]
+    Overflow(String),
+    /// A pure addition whose result is too long or too short for any sum of its words.
+    #[error("A sum of these words has {min} to {max} digits, but the result has {result}")]
+    ResultWidth {
+        result: usize,
+        min: usize,
+        max: usize,
+    },
+    #[error("Word {0} looks like two words without an operator between them")]
+    JoinedWords(String),
+    #[error("Conflicting constraints: {0}")]
+    ConflictingConstraints(String),
+    #[error(transparent)]
+    Solve(#[from] SolveError),
+    /// Reading the input failed; `solve_reader` stops after reporting this.
+    #[error("Could not read input: {0}")]
+    Read(String),
+    #[error("None of {0} generated puzzles had a unique solution")]
+    NoUniquePuzzle(usize),
+}
+
+/// How the two sides of an equation compare; plain cryptarithms use `Equal`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Relation {
+    #[default]
+    Equal,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+impl Relation {
+    fn holds<T: PartialOrd>(self, lhs: T, rhs: T) -> bool {
+        match self {
+            Relation::Equal => lhs == rhs,
+            Relation::Less => lhs < rhs,
+            Relation::LessEqual => lhs <= rhs,
+            Relation::Greater => lhs > rhs,
+            Relation::GreaterEqual => lhs >= rhs,
+        }
+    }
+}
+
+impl fmt::Display for Relation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Relation::Equal => "=",
+            Relation::Less => "<",
+            Relation::LessEqual => "<=",
+            Relation::Greater => ">",
+            Relation::GreaterEqual => ">=",
+        })
+    }
+}
+
+/// An extra condition between letters such as `A > B` or `C == D + 1`, added with
+/// `Equation::constrain`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Constraint {
+    lhs: Vec<Token>,
+    relation: Relation,
+    rhs: Vec<Token>,
+    letters: BTreeSet<char>,
+}
+
+impl Constraint {
+    /// `None` while some of its letters are still unassigned.
+    fn holds(&self, mapping: &BTreeMap<char, u8>, base: u8) -> Option<bool> {
+        if !self.letters.iter().all(|ch| mapping.contains_key(ch)) {
+            return None;
+        }
+        let lhs = eval_rpn(&self.lhs, mapping, base).ok()??;
+        let rhs = eval_rpn(&self.rhs, mapping, base).ok()??;
+        Some(self.relation.holds(lhs, rhs))
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(try_from = "EquationData")
+)]
 pub struct Equation {
     // Reverse Polish notation (RPN)
     rpn_token: Vec<Token>,
-    result: Vec<char>,
+    // Right-hand side of the relation, also in RPN
+    result_token: Vec<Token>,
+    relation: Relation,
+    // `r D` after the quotient of an `X / Y = C r D` puzzle
+    remainder_token: Option<Vec<Token>>,
     pub mapping: BTreeMap<char, u8>,
+    /// Prune pure-addition puzzles column by column as letters get assigned.
+    pub propagate_columns: bool,
+    /// Require every letter to take a different digit. Clear it for variants where
+    /// letters may share digits; leading letters still can't be zero.
+    pub unique_digits: bool,
+    /// Let the first letter of a word be zero.
+    pub leading_zeros: bool,
+    /// Limits the digits a letter may take, e.g. `'D' => 5..=9`. Letters without an
+    /// entry can take any digit. Ranges that clash with each other or with `mapping` just
+    /// leave no solution; `Equation::check_constraints` says why.
+    pub ranges: BTreeMap<char, RangeInclusive<u8>>,
+    constraints: Vec<Constraint>,
 
     unique_chars: BTreeSet<char>,
     special_chars: BTreeSet<char>,
+    base: u8,
 }
 
-fn apply_operation(equation: &Equation) -> anyhow::Result<bool> {
-    let mut stack = vec![];
-    for token in equation.rpn_token.iter() {
-        match token {
-            Token::Word(bytes) => {
-                let num = bytes
-                    .iter()
-                    .map(|c| *equation.mapping.get(c).unwrap_or(&1))
-                    .join("")
-                    .parse::<u32>()
-                    .unwrap();
-                stack.push(num);
+// What deserializing an `Equation` reads before checking it the way `parse_input` would
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct EquationData {
+    rpn_token: Vec<Token>,
+    result_token: Vec<Token>,
+    relation: Relation,
+    remainder_token: Option<Vec<Token>>,
+    mapping: BTreeMap<char, u8>,
+    propagate_columns: bool,
+    unique_digits: bool,
+    leading_zeros: bool,
+    ranges: BTreeMap<char, RangeInclusive<u8>>,
+    constraints: Vec<Constraint>,
+    unique_chars: BTreeSet<char>,
+    special_chars: BTreeSet<char>,
+    base: u8,
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<EquationData> for Equation {
+    type Error = CryptarithmError;
+
+    fn try_from(data: EquationData) -> Result<Self, Self::Error> {
+        if !(2..=36).contains(&data.base) {
+            return Err(CryptarithmError::UnsupportedBase(data.base));
+        }
+        let equation = Equation {
+            rpn_token: data.rpn_token,
+            result_token: data.result_token,
+            relation: data.relation,
+            remainder_token: data.remainder_token,
+            mapping: data.mapping,
+            propagate_columns: data.propagate_columns,
+            unique_digits: data.unique_digits,
+            leading_zeros: data.leading_zeros,
+            ranges: data.ranges,
+            constraints: data.constraints,
+            unique_chars: data.unique_chars,
+            special_chars: data.special_chars,
+            base: data.base,
+        };
+        equation.validate()?;
+        Ok(equation)
+    }
+}
+
+impl Equation {
+    fn new() -> Self {
+        Equation {
+            rpn_token: vec![],
+            result_token: vec![],
+            relation: Relation::Equal,
+            remainder_token: None,
+            mapping: BTreeMap::new(),
+            propagate_columns: false,
+            unique_digits: true,
+            leading_zeros: false,
+            ranges: BTreeMap::new(),
+            constraints: vec![],
+
+            unique_chars: BTreeSet::new(),
+            special_chars: BTreeSet::new(),
+            base: 10,
+        }
+    }
+
+    /// Adds a condition written like the puzzle itself, e.g. `A > B` or `C == D + 1`.
+    /// The search checks it as soon as all of its letters have digits, pruning the branch
+    /// when it fails.
+    pub fn constrain(mut self, constraint: &str) -> Result<Self, CryptarithmError> {
+        let mut scratch = Equation::new();
+        let Sides {
+            lhs,
+            relation,
+            rhs,
+            remainder,
+        } = parse_relation(constraint, &[], &mut scratch)?;
+        if remainder.is_some() {
+            return Err(CryptarithmError::InvalidExpression(
+                "constraints can't have a remainder".to_string(),
+            ));
+        }
+        let letters = scratch.unique_chars;
+        if let Some(&ch) = letters
+            .iter()
+            .find(|ch| !self.unique_chars.contains(ch) && !self.mapping.contains_key(ch))
+        {
+            return Err(CryptarithmError::UnknownLetter(ch));
+        }
+        // Catch malformed sides now rather than having every candidate fail
+        check_rpn(&lhs)?;
+        check_rpn(&rhs)?;
+        self.constraints.push(Constraint {
+            lhs,
+            relation,
+            rhs,
+            letters,
+        });
+        Ok(self)
+    }
+
+    /// Reinterprets the equation as a base-`base` cryptarithm (2..=36).
+    pub fn in_base(mut self, base: u8) -> Result<Self, CryptarithmError> {
+        if !(2..=36).contains(&base) {
+            return Err(CryptarithmError::UnsupportedBase(base));
+        }
+        if let Some((ch, digit)) = self.mapping.iter().find(|(_, &v)| v >= base) {
+            return Err(CryptarithmError::DigitOutOfRange {
+                letter: *ch,
+                digit: *digit,
+                base,
+            });
+        }
+        let revealed = self.tokens().find_map(|token| match token {
+            Token::Word(word) => word
+                .iter()
+                .filter_map(|ch| ch.to_digit(10))
+                .find(|&digit| digit >= base as u32)
+                .map(|digit| (word, digit)),
+            _ => None,
+        });
+        if let Some((word, digit)) = revealed {
+            return Err(CryptarithmError::InvalidExpression(format!(
+                "digit {} of {} is out of range for base {}",
+                digit,
+                word.iter().collect::<String>(),
+                base
+            )));
+        }
+        self.base = base;
+        self.check_letter_count()?;
+        check_widths(&self)?;
+        Ok(self)
+    }
+
+    fn check_letter_count(&self) -> Result<(), CryptarithmError> {
+        if !self.unique_digits {
+            return Ok(());
+        }
+        let letters = self.unique_chars.len() + self.mapping.len();
+        if letters > self.base as usize {
+            return Err(CryptarithmError::TooManyLetters {
+                letters,
+                base: self.base,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn base(&self) -> u8 {
+        self.base
+    }
+
+    pub fn relation(&self) -> Relation {
+        self.relation
+    }
+
+    /// Letters the solver still has to assign; ones seeded in `mapping` are left out.
+    pub fn unique_chars(&self) -> &BTreeSet<char> {
+        &self.unique_chars
+    }
+
+    /// Letters that start a word and so can't be zero unless `leading_zeros` is set.
+    pub fn special_chars(&self) -> &BTreeSet<char> {
+        &self.special_chars
+    }
+
+    /// Operators in evaluation order, left-hand side first; unary minus shows up as `-`.
+    pub fn operators(&self) -> Vec<char> {
+        self.tokens()
+            .filter_map(|token| match token {
+                Token::Operator(op) => Some(*op),
+                Token::Negate => Some('-'),
+                Token::Factorial => Some(FACTORIAL),
+                Token::Word(_) | Token::Number(_) => None,
+            })
+            .collect()
+    }
+
+    /// Words as they appear from left to right, on both sides of the relation.
+    pub fn words(&self) -> Vec<String> {
+        self.tokens()
+            .filter_map(|token| match token {
+                Token::Word(chars) => Some(chars.iter().collect()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Runs every structural check without searching: both sides are well-formed
+    /// expressions, there is a result, the letters fit in the base, the digits already in
+    /// `mapping` are in range and not leading zeros, `check_constraints` passes, and a
+    /// plain addition's result has a possible width. `Ok` doesn't mean there is a solution.
+    pub fn validate(&self) -> Result<(), CryptarithmError> {
+        if self.result_token.is_empty() {
+            return Err(CryptarithmError::MissingResult);
+        }
+        self.check_tokens()?;
+        for constraint in &self.constraints {
+            check_rpn(&constraint.lhs)?;
+            check_rpn(&constraint.rhs)?;
+        }
+        self.check_letter_count()?;
+        for (&ch, &digit) in &self.mapping {
+            if digit >= self.base {
+                return Err(CryptarithmError::DigitOutOfRange {
+                    letter: ch,
+                    digit,
+                    base: self.base,
+                });
             }
-            Token::Operator(op) => {
-                if stack.len() < 2 {
-                    anyhow::bail!("Invalid RPN expresion");
-                }
-                let b = stack.pop().unwrap();
-                let a = stack.pop().unwrap();
-                let res = match op {
-                    '+' => a + b,
-                    '-' => a - b,
-                    '*' => a * b,
-                    '/' => a / b,
-                    _ => anyhow::bail!("Invalid operator: {}", op),
-                };
-                stack.push(res);
+            if digit == 0 && !self.leading_zeros && self.special_chars.contains(&ch) {
+                return Err(CryptarithmError::LeadingZero(ch));
             }
         }
+        self.check_constraints()?;
+        check_widths(self)
     }
 
-    if let Some(output) = stack.pop() {
-        let result = equation
-            .result
+    /// Catches fixed digits and `ranges` that rule out every solution before any search,
+    /// with a `CryptarithmError::ConflictingConstraints` naming the clash: two letters
+    /// fixed to one digit, a fixed digit outside its letter's range, a letter left with no
+    /// digit to take, or more letters than digits among a group whose ranges overlap.
+    pub fn check_constraints(&self) -> Result<(), CryptarithmError> {
+        let conflict = |reason: String| Err(CryptarithmError::ConflictingConstraints(reason));
+        let mut seen: BTreeMap<u8, char> = BTreeMap::new();
+        for (&ch, &digit) in &self.mapping {
+            if let Some(range) = self.ranges.get(&ch).filter(|range| !range.contains(&digit)) {
+                return conflict(format!("{} is fixed to {} outside {:?}", ch, digit, range));
+            }
+            if let Some(&other) = seen.get(&digit).filter(|_| self.unique_digits) {
+                return conflict(format!("{} and {} are both fixed to {}", other, ch, digit));
+            }
+            seen.insert(digit, ch);
+        }
+        // Bit `d` of a letter's domain is set when it could still take digit `d`
+        let used = used_digits(&self.mapping);
+        let domains = self
+            .unique_chars
             .iter()
-            .map(|c| *equation.mapping.get(c).unwrap_or(&1))
-            .join("")
-            .parse::<u32>()?;
+            .map(|&ch| {
+                let domain = (0..self.base)
+                    .filter(|&digit| assign_blocker(self, ch, digit, used).is_none())
+                    .fold(0u64, |domain, digit| domain | 1 << digit);
+                (ch, domain)
+            })
+            .collect_vec();
+        for &(ch, domain) in &domains {
+            if domain == 0 {
+                return conflict(format!("no digit is left for {}", ch));
+            }
+            if !self.unique_digits {
+                continue;
+            }
+            let within: String = domains
+                .iter()
+                .filter(|&&(_, other)| other & !domain == 0)
+                .map(|&(other, _)| other)
+                .collect();
+            if within.chars().count() > domain.count_ones() as usize {
+                return conflict(format!(
+                    "{} need different digits but only {} are allowed",
+                    within,
+                    domain.count_ones()
+                ));
+            }
+        }
+        Ok(())
+    }
 
-        return Ok(output == result);
+    fn tokens(&self) -> impl Iterator<Item = &Token> {
+        self.rpn_token
+            .iter()
+            .chain(self.result_token.iter())
+            .chain(self.remainder_token.iter().flatten())
     }
-    anyhow::bail!("Invalid RPN expresion")
-}
 
-fn backtrack(
-    equation: &mut Equation,
-    digits: &[u8],
-    rx: &Sender<BTreeMap<char, u8>>,
-) -> anyhow::Result<bool> {
-    if equation.unique_chars.is_empty() {
-        return apply_operation(equation);
+    fn check_solvable(&self) -> Result<(), SolveError> {
+        match self.check_letter_count() {
+            Err(CryptarithmError::TooManyLetters { letters, base }) => {
+                Err(SolveError::TooManyLetters { letters, base })
+            }
+            _ => Ok(()),
+        }
     }
 
-    let ch = equation
-        .unique_chars
-        .pop_first()
-        .expect("unique_chars is empty");
-    for &digit in digits {
-        if digit == 0 && equation.special_chars.contains(&ch) {
-            continue;
+    fn check_tokens(&self) -> Result<(), CryptarithmError> {
+        check_rpn(&self.rpn_token)?;
+        check_rpn(&self.result_token)?;
+        match &self.remainder_token {
+            Some(remainder) => check_rpn(remainder),
+            None => Ok(()),
         }
-        if !equation.mapping.values().any(|&v| v == digit) {
-            equation.mapping.insert(ch, digit);
-            if backtrack(equation, digits, rx)? {
-                rx.send(equation.mapping.clone()).unwrap_or(());
-                return Ok(true);
+    }
+
+    fn check_mapping(&self, mapping: &BTreeMap<char, u8>) -> Result<(), CryptarithmError> {
+        for ch in self.unique_chars.iter().chain(self.mapping.keys()) {
+            match mapping.get(ch) {
+                None => return Err(CryptarithmError::MissingLetter(*ch)),
+                Some(&digit) if digit >= self.base => {
+                    return Err(CryptarithmError::DigitOutOfRange {
+                        letter: *ch,
+                        digit,
+                        base: self.base,
+                    })
+                }
+                _ => {}
             }
-            equation.mapping.remove(&ch);
         }
+        Ok(())
     }
-    equation.unique_chars.insert(ch);
-    Ok(false)
 }
 
-pub fn parse_input(expresion: &str) -> anyhow::Result<Equation> {
-    fn is_operator(c: char) -> bool {
-        c == '+' || c == '-' || c == '*' || c == '/'
+impl Equation {
+    fn infix<F>(
+        &self,
+        number_base: u8,
+        mut render_word: F,
+    ) -> Result<(String, String), CryptarithmError>
+    where
+        F: FnMut(&[char]) -> Result<String, CryptarithmError>,
+    {
+        let lhs = render_rpn(&self.rpn_token, number_base, &mut render_word)?;
+        let mut rhs = render_rpn(&self.result_token, number_base, &mut render_word)?;
+        if let Some(remainder) = &self.remainder_token {
+            let remainder = render_rpn(remainder, number_base, &mut render_word)?;
+            rhs = format!("{} r {}", rhs, remainder);
+        }
+        Ok((lhs, rhs))
+    }
+}
+
+fn render_rpn<F>(
+    tokens: &[Token],
+    number_base: u8,
+    render_word: &mut F,
+) -> Result<String, CryptarithmError>
+where
+    F: FnMut(&[char]) -> Result<String, CryptarithmError>,
+{
+    let mut stack: Vec<(String, i32)> = vec![];
+    for token in tokens.iter() {
+        match token {
+            Token::Word(chars) => stack.push((render_word(chars)?, i32::MAX)),
+            Token::Number(value) => stack.push((format_digits(*value, number_base), i32::MAX)),
+            Token::Operator(op) => {
+                let (Some((b, b_prec)), Some((a, a_prec))) = (stack.pop(), stack.pop()) else {
+                    return Err(CryptarithmError::InvalidRpn);
+                };
+                let prec = precedence(*op);
+                let right = is_right_associative(*op);
+                let a = if a_prec < prec || (right && a_prec == prec) {
+                    format!("({})", a)
+                } else {
+                    a
+                };
+                let b = if b_prec < prec || (!right && b_prec == prec) {
+                    format!("({})", b)
+                } else {
+                    b
+                };
+                stack.push((format!("{} {} {}", a, op, b), prec));
+            }
+            Token::Negate => {
+                let Some((a, a_prec)) = stack.pop() else {
+                    return Err(CryptarithmError::InvalidRpn);
+                };
+                let prec = precedence(NEGATE);
+                let a = if a_prec < prec { format!("({})", a) } else { a };
+                stack.push((format!("-{}", a), prec));
+            }
+            Token::Factorial => {
+                let Some((a, a_prec)) = stack.pop() else {
+                    return Err(CryptarithmError::InvalidRpn);
+                };
+                let prec = precedence(FACTORIAL);
+                let a = if a_prec < prec { format!("({})", a) } else { a };
+                stack.push((format!("{}!", a), prec));
+            }
+        }
+    }
+    match stack.pop() {
+        Some((rendered, _)) if stack.is_empty() => Ok(rendered),
+        _ => Err(CryptarithmError::InvalidRpn),
+    }
+}
+
+impl fmt::Display for Equation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (lhs, rhs) = self
+            .infix(10, |chars| Ok(chars.iter().collect()))
+            .map_err(|_| fmt::Error)?;
+        write!(f, "{} {} {}", lhs, self.relation, rhs)
+    }
+}
+
+/// A binary infix operator, looked up by its symbol when parsing and evaluating.
+#[derive(Debug, Clone, Copy)]
+pub struct BinaryOperator {
+    /// Binds tighter the higher it is: `+`/`-` are 1, `*`/`/`/`%` are 2 and `^` is 4
+    /// (unary minus sits at 3). Must be at least 1.
+    pub precedence: i32,
+    pub right_associative: bool,
+    /// `None` rejects the candidate, e.g. on overflow or an inexact quotient.
+    pub apply: fn(i64, i64) -> Option<i64>,
+}
+
+const fn left(precedence: i32, apply: fn(i64, i64) -> Option<i64>) -> BinaryOperator {
+    BinaryOperator {
+        precedence,
+        right_associative: false,
+        apply,
+    }
+}
+
+const BUILTIN_OPERATORS: [(char, BinaryOperator); 6] = [
+    ('+', left(1, i64::checked_add)),
+    ('-', left(1, i64::checked_sub)),
+    ('*', left(2, i64::checked_mul)),
+    // A zero divisor or an inexact quotient both reject the candidate
+    (
+        '/',
+        left(2, |a, b| {
+            a.checked_rem(b)
+                .filter(|&rem| rem == 0)
+                .and_then(|_| a.checked_div(b))
+        }),
+    ),
+    ('%', left(2, i64::checked_rem)),
+    (
+        '^',
+        BinaryOperator {
+            precedence: 4,
+            right_associative: true,
+            apply: |a, b| u32::try_from(b).ok().and_then(|b| a.checked_pow(b)),
+        },
+    ),
+];
+
+static CUSTOM_OPERATORS: RwLock<BTreeMap<char, BinaryOperator>> = RwLock::new(BTreeMap::new());
+
+/// Makes `symbol` usable as a binary operator in every equation parsed afterwards, e.g.
+/// a `max` written as `|`. The built-in operators can't be replaced, and neither can
+/// letters, digits, whitespace, parentheses, relations, `!`, `~`, backticks or `;`.
+///
+/// Each symbol can be registered only once. An `Equation` keeps just the symbol, so
+/// changing its meaning would leave already parsed equations, and solutions cached by
+/// the `cache` feature, computed with the old one.
+pub fn register_operator(symbol: char, operator: BinaryOperator) -> Result<(), CryptarithmError> {
+    let reserved = symbol.is_alphanumeric()
+        || symbol.is_whitespace()
+        || ['(', ')', '<', '>', '=', ';', NEGATE, FACTORIAL, QUOTE].contains(&symbol)
+        || BUILTIN_OPERATORS
+            .iter()
+            .any(|(builtin, _)| *builtin == symbol);
+    if reserved || operator.precedence < 1 {
+        return Err(CryptarithmError::InvalidOperator(symbol));
+    }
+    match CUSTOM_OPERATORS
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .entry(symbol)
+    {
+        Entry::Vacant(entry) => {
+            entry.insert(operator);
+            Ok(())
+        }
+        Entry::Occupied(_) => Err(CryptarithmError::InvalidOperator(symbol)),
+    }
+}
+
+fn binary_operator(symbol: char) -> Option<BinaryOperator> {
+    match BUILTIN_OPERATORS
+        .iter()
+        .find(|(builtin, _)| *builtin == symbol)
+    {
+        Some((_, operator)) => Some(*operator),
+        None => CUSTOM_OPERATORS
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .get(&symbol)
+            .copied(),
+    }
+}
+
+// Stands in for unary minus on the operator stack
+const NEGATE: char = '~';
+// Postfix, so it goes straight to the output instead of the operator stack
+const FACTORIAL: char = '!';
+// Whitespace between a pair of these doesn't end a word
+const QUOTE: char = '`';
+
+fn is_operator(c: char) -> bool {
+    binary_operator(c).is_some()
+}
+
+fn precedence(op: char) -> i32 {
+    match op {
+        NEGATE => 3,
+        FACTORIAL => 5,
+        _ => binary_operator(op).map_or(0, |operator| operator.precedence),
+    }
+}
+
+fn is_right_associative(op: char) -> bool {
+    op == NEGATE || binary_operator(op).is_some_and(|operator| operator.right_associative)
+}
+
+fn operator_token(op: char) -> Token {
+    match op {
+        NEGATE => Token::Negate,
+        _ => Token::Operator(op),
     }
+}
 
-    fn precedence(op: char) -> i32 {
-        match op {
-            '+' | '-' => 1,
-            '*' | '/' => 2,
-            _ => 0,
+fn push_operator(rpn_token: &mut Vec<Token>, operators: &mut Vec<char>, op: char) {
+    // A prefix operator has no left operand yet, so there is nothing to reduce
+    if op != NEGATE {
+        while let Some(&top) = operators.last() {
+            if precedence(top) > precedence(op)
+                || (precedence(top) == precedence(op) && !is_right_associative(op))
+            {
+                rpn_token.push(operator_token(operators.pop().unwrap()))
+            } else {
+                break;
+            }
         }
     }
+    operators.push(op)
+}
+
+// What expressions evaluate to. By default that's i64, with overflow rejecting the
+// candidate; the `bigint` feature trades speed for words of any length, though products,
+// powers and factorials still stop at `MAX_PRODUCT_BITS`.
+#[cfg(not(feature = "bigint"))]
+type Value = i64;
+#[cfg(feature = "bigint")]
+type Value = num_bigint::BigInt;
+
+// Products, powers and factorials past this many bits reject the candidate, so one leaf
+// like `987654!` can't stall the search between deadline checks
+#[cfg(feature = "bigint")]
+const MAX_PRODUCT_BITS: u64 = 4096;
+
+/// A word's digits: the mapping's for letters, and their own for the decimal digits a
+/// partially revealed word like `S9ND` keeps in place.
+fn word_digits(word: &[char], mapping: &BTreeMap<char, u8>) -> Result<Vec<u8>, CryptarithmError> {
+    word.iter()
+        .map(|ch| match ch.to_digit(10) {
+            Some(digit) => Ok(digit as u8),
+            None => mapping
+                .get(ch)
+                .copied()
+                .ok_or(CryptarithmError::MissingLetter(*ch)),
+        })
+        .collect()
+}
+
+/// `Ok(None)` when the word overflows.
+#[cfg(not(feature = "bigint"))]
+fn word_value(
+    word: &[char],
+    mapping: &BTreeMap<char, u8>,
+    base: u8,
+) -> Result<Option<Value>, CryptarithmError> {
+    Ok(word_digits(word, mapping)?
+        .into_iter()
+        .try_fold(0i64, |acc, digit| {
+            acc.checked_mul(base as i64)?.checked_add(digit as i64)
+        }))
+}
+
+#[cfg(feature = "bigint")]
+fn word_value(
+    word: &[char],
+    mapping: &BTreeMap<char, u8>,
+    base: u8,
+) -> Result<Option<Value>, CryptarithmError> {
+    let digits = word_digits(word, mapping)?;
+    Ok(Value::from_radix_be(
+        num_bigint::Sign::Plus,
+        &digits,
+        base as u32,
+    ))
+}
+
+#[cfg(not(feature = "bigint"))]
+fn number_value(value: u64) -> Option<Value> {
+    i64::try_from(value).ok()
+}
+
+#[cfg(feature = "bigint")]
+fn number_value(value: u64) -> Option<Value> {
+    Some(Value::from(value))
+}
+
+#[cfg(not(feature = "bigint"))]
+fn value_to_i64(value: Value) -> Option<i64> {
+    Some(value)
+}
+
+#[cfg(feature = "bigint")]
+fn value_to_i64(value: Value) -> Option<i64> {
+    i64::try_from(value).ok()
+}
+
+#[cfg(not(feature = "bigint"))]
+fn apply_binary(_symbol: char, operator: BinaryOperator, a: Value, b: Value) -> Option<Value> {
+    (operator.apply)(a, b)
+}
 
-    let mut expresion = expresion.to_string();
-    for op in ['+', '-', '*', '/'] {
-        expresion = expresion.replace(op, &format!(" {} ", op));
+/// The built-in operators get exact big-integer versions; custom ones only ever see
+/// operands that fit in an i64.
+#[cfg(feature = "bigint")]
+fn apply_binary(symbol: char, operator: BinaryOperator, a: Value, b: Value) -> Option<Value> {
+    use num_bigint::Sign;
+    match symbol {
+        '+' => Some(a + b),
+        '-' => Some(a - b),
+        '*' if a.bits() + b.bits() <= MAX_PRODUCT_BITS + 1 => Some(a * b),
+        '*' => None,
+        '/' if b.sign() != Sign::NoSign && (&a % &b).sign() == Sign::NoSign => Some(a / b),
+        '%' if b.sign() != Sign::NoSign => Some(a % b),
+        '/' | '%' => None,
+        // `a^b` has at least `(bits(a) - 1) * b + 1` bits
+        '^' => {
+            let b = u32::try_from(&b).ok()?;
+            let low_bits = a.bits().saturating_sub(1).saturating_mul(b as u64);
+            (low_bits < MAX_PRODUCT_BITS).then(|| a.pow(b))
+        }
+        _ => (operator.apply)(i64::try_from(&a).ok()?, i64::try_from(&b).ok()?).map(Value::from),
     }
+}
+
+#[cfg(not(feature = "bigint"))]
+fn negate(a: Value) -> Option<Value> {
+    a.checked_neg()
+}
+
+#[cfg(feature = "bigint")]
+fn negate(a: Value) -> Option<Value> {
+    Some(-a)
+}
+
+/// Negative operands have no factorial.
+#[cfg(not(feature = "bigint"))]
+fn factorial(a: Value) -> Option<Value> {
+    // Anything past 20! overflows
+    (a >= 0)
+        .then(|| (1..=a).try_fold(1i64, |acc, n| acc.checked_mul(n)))
+        .flatten()
+}
+
+#[cfg(feature = "bigint")]
+fn factorial(a: Value) -> Option<Value> {
+    let n = u64::try_from(&a).ok()?;
+    (1..=n).try_fold(Value::from(1), |acc, k| {
+        let product = acc * k;
+        (product.bits() <= MAX_PRODUCT_BITS).then_some(product)
+    })
+}
+
+/// `a * b + c`
+#[cfg(not(feature = "bigint"))]
+fn mul_add(a: &Value, b: &Value, c: &Value) -> Option<Value> {
+    a.checked_mul(*b)?.checked_add(*c)
+}
+
+#[cfg(feature = "bigint")]
+fn mul_add(a: &Value, b: &Value, c: &Value) -> Option<Value> {
+    (a.bits() + b.bits() <= MAX_PRODUCT_BITS + 1).then(|| a * b + c)
+}
 
-    let mut equation = Equation {
-        rpn_token: vec![],
-        result: vec![],
-        mapping: BTreeMap::new(),
+fn apply_operation(
+    equation: &Equation,
+    mapping: &BTreeMap<char, u8>,
+) -> Result<bool, CryptarithmError> {
+    if let Some(remainder) = &equation.remainder_token {
+        return apply_division(equation, remainder, mapping);
+    }
+    let lhs = eval_rpn(&equation.rpn_token, mapping, equation.base)?;
+    let rhs = eval_rpn(&equation.result_token, mapping, equation.base)?;
+    match (lhs, rhs) {
+        (Some(lhs), Some(rhs)) => Ok(equation.relation.holds(lhs, rhs)),
+        _ => Ok(false),
+    }
+}
 
-        unique_chars: BTreeSet::new(),
-        special_chars: BTreeSet::new(),
+/// Checks `X / Y = C r D` as `X == Y * C + D` with `0 <= D < Y`.
+fn apply_division(
+    equation: &Equation,
+    remainder: &[Token],
+    mapping: &BTreeMap<char, u8>,
+) -> Result<bool, CryptarithmError> {
+    // The parser only accepts a remainder when `/` is the last thing evaluated
+    let Some((Token::Operator('/'), operands)) = equation.rpn_token.split_last() else {
+        return Err(CryptarithmError::InvalidRpn);
     };
+    let operands = eval_stack(operands, mapping, equation.base)?;
+    let quotient = eval_rpn(&equation.result_token, mapping, equation.base)?;
+    let remainder = eval_rpn(remainder, mapping, equation.base)?;
+    let (Some([dividend, divisor]), Some(quotient), Some(remainder)) =
+        (operands.as_deref(), quotient, remainder)
+    else {
+        return Ok(false);
+    };
+    Ok(remainder >= Value::from(0)
+        && &remainder < divisor
+        && mul_add(divisor, &quotient, &remainder).as_ref() == Some(dividend))
+}
 
-    let mut buffer = vec![];
-    let mut operators = vec![];
-    for ch in expresion.chars() {
-        if ch.is_alphabetic() {
-            equation.unique_chars.insert(ch);
-            buffer.push(ch);
-            continue;
-        } else if !is_operator(ch) && ch != ' ' && ch != '=' {
-            anyhow::bail!("Invalid token {}", ch);
+/// Checks that `tokens` make up one whole expression of known operators, without
+/// evaluating anything, so it works before any letter has a digit.
+fn check_rpn(tokens: &[Token]) -> Result<(), CryptarithmError> {
+    let mut depth = 0;
+    for token in tokens.iter() {
+        let operands = match token {
+            Token::Word(_) | Token::Number(_) => 0,
+            Token::Operator(op) if !is_operator(*op) => {
+                return Err(CryptarithmError::InvalidOperator(*op));
+            }
+            Token::Operator(_) => 2,
+            Token::Negate | Token::Factorial => 1,
+        };
+        if depth < operands {
+            return Err(CryptarithmError::InvalidRpn);
         }
+        depth = depth - operands + 1;
+    }
+    if depth == 1 {
+        Ok(())
+    } else {
+        Err(CryptarithmError::InvalidRpn)
+    }
+}
 
-        if !buffer.is_empty() {
-            equation.special_chars.insert(buffer[0]);
-            equation.rpn_token.push(Token::Word(buffer.split_off(0)));
-        } else if is_operator(ch) {
-            while let Some(&top) = operators.last() {
-                if precedence(top) >= precedence(ch) {
-                    equation
-                        .rpn_token
-                        .push(Token::Operator(operators.pop().unwrap()))
-                } else {
-                    break;
+/// `Ok(None)` means the candidate broke the arithmetic (overflow, a zero divisor, ...).
+/// Every letter has to be in `mapping`; a missing one is `CryptarithmError::MissingLetter`.
+fn eval_rpn(
+    tokens: &[Token],
+    mapping: &BTreeMap<char, u8>,
+    base: u8,
+) -> Result<Option<Value>, CryptarithmError> {
+    match eval_stack(tokens, mapping, base)?.map(<[Value; 1]>::try_from) {
+        None => Ok(None),
+        Some(Ok([output])) => Ok(Some(output)),
+        Some(Err(_)) => Err(CryptarithmError::InvalidRpn),
+    }
+}
+
+/// Evaluates `tokens` and returns whatever is left on the stack.
+fn eval_stack(
+    tokens: &[Token],
+    mapping: &BTreeMap<char, u8>,
+    base: u8,
+) -> Result<Option<Vec<Value>>, CryptarithmError> {
+    let mut stack = vec![];
+    for token in tokens.iter() {
+        match token {
+            // Overflow anywhere just means this candidate is not a solution
+            Token::Word(bytes) => match word_value(bytes, mapping, base)? {
+                Some(num) => stack.push(num),
+                None => return Ok(None),
+            },
+            Token::Number(value) => match number_value(*value) {
+                Some(num) => stack.push(num),
+                None => return Ok(None),
+            },
+            Token::Operator(op) => {
+                if stack.len() < 2 {
+                    return Err(CryptarithmError::InvalidRpn);
+                }
+                let b = stack.pop().unwrap();
+                let a = stack.pop().unwrap();
+                let Some(operator) = binary_operator(*op) else {
+                    return Err(CryptarithmError::InvalidOperator(*op));
+                };
+                match apply_binary(*op, operator, a, b) {
+                    Some(res) => stack.push(res),
+                    None => return Ok(None),
+                }
+            }
+            Token::Negate => {
+                let Some(a) = stack.pop() else {
+                    return Err(CryptarithmError::InvalidRpn);
+                };
+                match negate(a) {
+                    Some(res) => stack.push(res),
+                    None => return Ok(None),
+                }
+            }
+            Token::Factorial => {
+                let Some(a) = stack.pop() else {
+                    return Err(CryptarithmError::InvalidRpn);
+                };
+                match factorial(a) {
+                    Some(res) => stack.push(res),
+                    None => return Ok(None),
                 }
             }
-            operators.push(ch)
         }
     }
-    if !buffer.is_empty() {
-        equation.special_chars.insert(buffer[0]);
-        equation.result = buffer
+
+    Ok(Some(stack))
+}
+
+/// Checks whether `mapping` satisfies the equation's arithmetic, ignoring `equation.mapping`.
+pub fn evaluate(
+    equation: &Equation,
+    mapping: &BTreeMap<char, u8>,
+) -> Result<bool, CryptarithmError> {
+    equation.check_mapping(mapping)?;
+    apply_operation(equation, mapping)
+}
+
+fn format_digits(mut value: u64, base: u8) -> String {
+    let mut digits = vec![];
+    loop {
+        digits.push(char::from_digit((value % base as u64) as u32, base as u32).unwrap());
+        value /= base as u64;
+        if value == 0 {
+            break;
+        }
     }
+    digits.iter().rev().collect()
+}
 
-    while let Some(op) = operators.pop() {
-        equation.rpn_token.push(Token::Operator(op));
+#[cfg(not(feature = "bigint"))]
+fn format_value(value: &Value, base: u8) -> String {
+    let digits = format_digits(value.unsigned_abs(), base);
+    if *value < 0 {
+        format!("-{}", digits)
+    } else {
+        digits
     }
-    apply_operation(&equation)?;
-    Ok(equation)
 }
 
-#[cached(size = 1024, time = 120)]
-pub fn solve(equation: Equation) -> BTreeMap<char, u8> {
-    let (rx, tx) = crossbeam_channel::bounded(1);
+#[cfg(feature = "bigint")]
+fn format_value(value: &Value, base: u8) -> String {
+    value.to_str_radix(base as u32)
+}
+
+/// Renders the equation with every letter replaced by its digit, e.g. `9567 + 1085 = 10652`.
+pub fn format_solution(
+    equation: &Equation,
+    mapping: &BTreeMap<char, u8>,
+) -> Result<String, CryptarithmError> {
+    equation.check_mapping(mapping)?;
+    let (lhs, rhs) = equation.infix(equation.base, |chars| {
+        Ok(format_value(
+            &word_value(chars, mapping, equation.base)?
+                .ok_or_else(|| CryptarithmError::Overflow(chars.iter().collect()))?,
+            equation.base,
+        ))
+    })?;
+    Ok(format!("{} {} {}", lhs, equation.relation, rhs))
+}
+
+/// The value of each word and literal on the left-hand side, in order, and the value
+/// of the right-hand side under `mapping`. A value past i64, which only the `bigint`
+/// feature can produce, is a `CryptarithmError::Overflow`.
+pub fn solved_values(
+    equation: &Equation,
+    mapping: &BTreeMap<char, u8>,
+) -> Result<(Vec<i64>, i64), CryptarithmError> {
+    equation.check_mapping(mapping)?;
+    let operands = equation
+        .rpn_token
+        .iter()
+        .filter_map(|token| match token {
+            Token::Word(chars) => {
+                Some(word_value(chars, mapping, equation.base).and_then(|value| {
+                    value
+                        .and_then(value_to_i64)
+                        .ok_or_else(|| CryptarithmError::Overflow(chars.iter().collect()))
+                }))
+            }
+            Token::Number(value) => Some(
+                i64::try_from(*value).map_err(|_| CryptarithmError::Overflow(value.to_string())),
+            ),
+            _ => None,
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let result = eval_rpn(&equation.result_token, mapping, equation.base)?
+        .and_then(value_to_i64)
+        .ok_or_else(|| {
+            let rendered = render_rpn(&equation.result_token, 10, &mut |chars| {
+                Ok(chars.iter().collect())
+            });
+            CryptarithmError::Overflow(rendered.unwrap_or_default())
+        })?;
+    Ok((operands, result))
+}
 
-    let mut handlers = vec![];
-    for digits in [(0..=9).rev().collect_vec(), (0..=9).collect_vec()] {
-        let mut equation = equation.clone();
-        let rx = rx.clone();
-        handlers.push(thread::spawn(move || {
-            backtrack(&mut equation, &digits, &rx).unwrap_or_default();
-        }));
+/// Flips a solution into digit → letter. If letters share a digit (see
+/// `Equation::unique_digits`), the alphabetically first of them is kept.
+pub fn invert_mapping(mapping: &BTreeMap<char, u8>) -> BTreeMap<u8, char> {
+    let mut inverted = BTreeMap::new();
+    for (&ch, &digit) in mapping {
+        inverted.entry(digit).or_insert(ch);
     }
+    inverted
+}
 
-    thread::spawn(move || {
-        for task in handlers {
-            task.join().unwrap();
+/// Like `invert_mapping`, but with an entry for every digit of the equation's base, `None`
+/// for the digits no letter took.
+pub fn full_assignment(
+    equation: &Equation,
+    mapping: &BTreeMap<char, u8>,
+) -> BTreeMap<u8, Option<char>> {
+    let inverted = invert_mapping(mapping);
+    (0..equation.base)
+        .map(|digit| (digit, inverted.get(&digit).copied()))
+        .collect()
+}
+
+/// One line per digit of the equation's base, listing the letters that took it or `-`
+/// when none did, e.g. `0: O`, `1: M`, `3: -`.
+pub fn format_digit_table(equation: &Equation, mapping: &BTreeMap<char, u8>) -> String {
+    (0..equation.base)
+        .map(|digit| {
+            let letters: String = mapping
+                .iter()
+                .filter(|(_, &v)| v == digit)
+                .map(|(&ch, _)| ch)
+                .collect();
+            let letters = if letters.is_empty() {
+                "-".to_string()
+            } else {
+                letters
+            };
+            format!(
+                "{}: {}",
+                format_digits(digit as u64, equation.base),
+                letters
+            )
+        })
+        .join("\n")
+}
+
+/// A solver's answer as JSON, e.g. `{"solved":true,"mapping":{"A":1,"B":2},
+/// "operands":[1],"result":2,"equation":"1 = 2"}` with `operands` and `result` from
+/// `solved_values` and `equation` from `format_solution`. For `A / B = C r D`, `result`
+/// is the quotient and a `"remainder"` key follows it. `None` gives `{"solved":false}`.
+/// Keys always come in this order and the letters in `char` order.
+pub fn solution_to_json(
+    equation: &Equation,
+    mapping: Option<&BTreeMap<char, u8>>,
+) -> Result<String, CryptarithmError> {
+    let Some(mapping) = mapping else {
+        return Ok(r#"{"solved":false}"#.to_string());
+    };
+    let (operands, result) = solved_values(equation, mapping)?;
+    let remainder = match &equation.remainder_token {
+        Some(remainder) => {
+            let value = eval_rpn(remainder, mapping, equation.base)?
+                .and_then(value_to_i64)
+                .ok_or_else(|| {
+                    let rendered =
+                        render_rpn(remainder, 10, &mut |chars| Ok(chars.iter().collect()));
+                    CryptarithmError::Overflow(rendered.unwrap_or_default())
+                })?;
+            format!(r#","remainder":{}"#, value)
         }
-        rx.send(BTreeMap::new()).unwrap_or_default();
-    });
-    tx.recv_timeout(Duration::from_secs(7)).unwrap_or_default()
+        None => String::new(),
+    };
+    // Only the equation's own letters are listed, and letters never need escaping
+    Ok(format!(
+        r#"{{"solved":true,"mapping":{{{}}},"operands":[{}],"result":{}{},"equation":"{}"}}"#,
+        equation
+            .unique_chars
+            .iter()
+            .chain(equation.mapping.keys())
+            .sorted()
+            .map(|ch| format!(r#""{}":{}"#, ch, mapping[ch]))
+            .join(","),
+        operands.iter().join(","),
+        result,
+        remainder,
+        json_escape(&format_solution(equation, mapping)?)
+    ))
+}
+
+/// Escapes `text` for a JSON string; a registered operator may be `"` or `\`.
+fn json_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            ch if ch.is_control() => escaped.push_str(&format!("\\u{:04x}", ch as u32)),
+            ch => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    nodes: AtomicU64,
+    assignments: AtomicU64,
+    depth: AtomicUsize,
+    // Set once the worker's branch has been searched
+    #[cfg(feature = "threads")]
+    finished: AtomicBool,
+}
+
+/// Per-worker search state shared with whoever drives the worker.
+struct Search {
+    digits: Vec<u8>,
+    // Order to assign letters in, see `search_order`
+    order: Vec<char>,
+    // Shared by every parallel branch of one solve
+    columns: Option<Arc<[Column]>>,
+    // Units column of a pure addition, checked even without `propagate_columns`
+    units: Option<Arc<Column>>,
+    cancel: Arc<AtomicBool>,
+    // Set by whichever parallel branch finds a solution first
+    found: Arc<AtomicBool>,
+    counters: Arc<Counters>,
+    deadline: Option<Instant>,
+    // Stop after visiting this many nodes
+    budget: Option<u64>,
+    // Only `solve_traced` records events
+    trace: Option<RefCell<Vec<TraceEvent>>>,
+}
+
+impl Search {
+    fn new(equation: &Equation) -> Self {
+        // Full propagation already covers the units column
+        let (columns, units) = match addition_columns(equation) {
+            Some(columns) if equation.propagate_columns => (Some(Arc::from(columns)), None),
+            Some(mut columns) if !columns.is_empty() => {
+                (None, Some(Arc::new(columns.swap_remove(0))))
+            }
+            _ => (None, None),
+        };
+        Search {
+            digits: (0..equation.base).collect_vec(),
+            order: search_order(equation),
+            columns,
+            units,
+            cancel: Arc::default(),
+            found: Arc::default(),
+            counters: Arc::default(),
+            deadline: None,
+            budget: None,
+            trace: None,
+        }
+    }
+
+    fn record(&self, event: TraceEvent) {
+        if let Some(trace) = &self.trace {
+            trace.borrow_mut().push(event);
+        }
+    }
+
+    fn stopped(&self) -> bool {
+        self.cancel.load(Ordering::Relaxed) || self.found.load(Ordering::Relaxed)
+    }
+
+    fn past_deadline(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+}
+
+/// Assigns letters nearest the units column first, so a wrong partial assignment makes a
+/// column fail as early as possible, breaking ties by how often the letter is used.
+fn search_order(equation: &Equation) -> Vec<char> {
+    let mut rank: BTreeMap<char, (usize, usize)> = BTreeMap::new();
+    for token in equation.tokens() {
+        if let Token::Word(word) = token {
+            for (place, &ch) in word.iter().rev().enumerate() {
+                let (lowest, uses) = rank.entry(ch).or_insert((usize::MAX, 0));
+                *lowest = (*lowest).min(place);
+                *uses += 1;
+            }
+        }
+    }
+    equation
+        .unique_chars
+        .iter()
+        .copied()
+        .sorted_by_key(|ch| {
+            let (lowest, uses) = rank.get(ch).copied().unwrap_or((usize::MAX, 0));
+            (lowest, Reverse(uses), *ch)
+        })
+        .collect()
+}
+
+/// Letters of one column of an addition, least significant column first.
+#[derive(Debug)]
+struct Column {
+    addends: Vec<char>,
+    result: Option<char>,
+}
+
+fn addition_columns(equation: &Equation) -> Option<Vec<Column>> {
+    if equation.relation != Relation::Equal {
+        return None;
+    }
+    let mut addends = vec![];
+    for token in equation.rpn_token.iter() {
+        match token {
+            Token::Word(word) if !word.iter().any(char::is_ascii_digit) => addends.push(word),
+            Token::Operator('+') => {}
+            // Column propagation only knows about letters, so numbers, revealed digits
+            // and any other operator opt out
+            _ => return None,
+        }
+    }
+    let [Token::Word(result)] = &equation.result_token[..] else {
+        return None;
+    };
+    if result.iter().any(char::is_ascii_digit) {
+        return None;
+    }
+    let width = addends
+        .iter()
+        .map(|word| word.len())
+        .chain([result.len()])
+        .max()
+        .unwrap_or(0);
+    let columns = (0..width)
+        .map(|i| Column {
+            addends: addends
+                .iter()
+                .filter_map(|word| word.iter().rev().nth(i).copied())
+                .collect(),
+            result: result.iter().rev().nth(i).copied(),
+        })
+        .collect();
+    Some(columns)
+}
+
+/// Walks the columns tracking the range the carry can take, and reports whether some
+/// completion of the partial `mapping` could still make every column add up.
+fn columns_consistent(columns: &[Column], mapping: &BTreeMap<char, u8>, base: u8) -> bool {
+    let base = base as u64;
+    let (mut carry_lo, mut carry_hi) = (0, 0);
+    for column in columns {
+        let mut known = 0;
+        let mut unknown = 0;
+        for ch in column.addends.iter() {
+            match mapping.get(ch) {
+                Some(&digit) => known += digit as u64,
+                None => unknown += 1,
+            }
+        }
+        let lo = known + carry_lo;
+        let hi = known + carry_hi + unknown * (base - 1);
+        // A column past the end of the result has to come out as zero
+        let target = match column.result {
+            Some(ch) => mapping.get(&ch).map(|&digit| digit as u64),
+            None => Some(0),
+        };
+        match target {
+            Some(target) => {
+                let Some(first) = (lo..=hi).find(|sum| sum % base == target) else {
+                    return false;
+                };
+                let last = (lo..=hi).rev().find(|sum| sum % base == target).unwrap();
+                (carry_lo, carry_hi) = (first / base, last / base);
+            }
+            None => (carry_lo, carry_hi) = (lo / base, hi / base),
+        }
+    }
+    carry_lo == 0
+}
+
+/// Reports whether the units column still adds up; only `false` once all its letters are
+/// assigned and their sum misses the result's last digit.
+fn units_consistent(units: &Column, mapping: &BTreeMap<char, u8>, base: u8) -> bool {
+    let mut sum = 0;
+    for ch in units.addends.iter() {
+        match mapping.get(ch) {
+            Some(&digit) => sum += digit as u64,
+            None => return true,
+        }
+    }
+    match units.result.map(|ch| mapping.get(&ch)) {
+        Some(Some(&target)) => sum % base as u64 == target as u64,
+        Some(None) => true,
+        None => sum % base as u64 == 0,
+    }
+}
+
+fn can_assign(equation: &Equation, ch: char, digit: u8) -> bool {
+    assign_blocker(equation, ch, digit, used_digits(&equation.mapping)).is_none()
+}
+
+/// Bit `d` is set when some letter has digit `d`; bases stop at 36, so a u64 fits them all.
+fn used_digits(mapping: &BTreeMap<char, u8>) -> u64 {
+    mapping.values().fold(0, |used, &digit| used | 1 << digit)
+}
+
+/// Why `ch` can't take `digit` right now, if it can't. `used` is `used_digits` of the
+/// equation's mapping.
+fn assign_blocker(equation: &Equation, ch: char, digit: u8, used: u64) -> Option<PruneReason> {
+    if digit == 0 && !equation.leading_zeros && equation.special_chars.contains(&ch) {
+        trace!("pruned {ch} = 0: leading zero");
+        return Some(PruneReason::LeadingZero { letter: ch });
+    }
+    if equation
+        .ranges
+        .get(&ch)
+        .is_some_and(|range| !range.contains(&digit))
+    {
+        trace!("pruned {ch} = {digit}: outside its range");
+        return Some(PruneReason::OutOfRange { letter: ch, digit });
+    }
+    if equation.unique_digits && used & 1 << digit != 0 {
+        trace!("pruned {ch} = {digit}: digit already taken");
+        return Some(PruneReason::DuplicateDigit { letter: ch, digit });
+    }
+    None
+}
+
+/// One step of the search, as recorded by `solve_traced`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceEvent {
+    /// `letter` takes `digit` and the search goes one level deeper.
+    Enter { letter: char, digit: u8 },
+    /// The search went no further here. Reasons naming a letter refused that digit
+    /// before any `Enter`; the others rule out the assignment made so far.
+    Prune(PruneReason),
+    /// `letter` gives back the digit of its latest `Enter`.
+    Backtrack { letter: char },
+    /// Every letter has a digit and the equation holds.
+    Solution,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PruneReason {
+    LeadingZero {
+        letter: char,
+    },
+    DuplicateDigit {
+        letter: char,
+        digit: u8,
+    },
+    /// The digit is outside the letter's entry in `Equation::ranges`.
+    OutOfRange {
+        letter: char,
+        digit: u8,
+    },
+    /// Fewer digits are left than letters still to assign.
+    TooFewDigits,
+    /// A column of the addition can no longer add up.
+    Columns,
+    /// One of the `Equation::constrain` constraints fails.
+    Constraint,
+    /// Every letter has a digit but the equation doesn't hold.
+    Evaluation,
+}
+
+const DEADLINE_CHECK_INTERVAL: u64 = 1024;
+
+fn backtrack<F>(
+    equation: &mut Equation,
+    search: &Search,
+    on_solution: &mut F,
+) -> Result<bool, CryptarithmError>
+where
+    F: FnMut(&BTreeMap<char, u8>) -> bool,
+{
+    // A result that can't be as wide as the sum has nothing to search
+    if check_widths(equation).is_err() {
+        return Ok(false);
+    }
+    let used = used_digits(&equation.mapping);
+    backtrack_from(equation, search, used, on_solution)
+}
+
+/// `backtrack` with the digits taken so far kept as a bitmask alongside the mapping, so
+/// checking a candidate doesn't scan it.
+fn backtrack_from<F>(
+    equation: &mut Equation,
+    search: &Search,
+    used: u64,
+    on_solution: &mut F,
+) -> Result<bool, CryptarithmError>
+where
+    F: FnMut(&BTreeMap<char, u8>) -> bool,
+{
+    if search.stopped() {
+        return Ok(true);
+    }
+    let counters = &search.counters;
+    let nodes = counters.nodes.fetch_add(1, Ordering::Relaxed);
+    // Reading the clock costs more than a node, so only look every so often
+    if nodes.is_multiple_of(DEADLINE_CHECK_INTERVAL) && search.past_deadline() {
+        return Ok(true);
+    }
+    if search.budget.is_some_and(|budget| nodes >= budget) {
+        return Ok(true);
+    }
+    counters
+        .depth
+        .store(equation.mapping.len(), Ordering::Relaxed);
+    if let Some(columns) = &search.columns {
+        if !columns_consistent(columns, &equation.mapping, equation.base) {
+            trace!("pruned {:?}: column sums can't work out", equation.mapping);
+            search.record(TraceEvent::Prune(PruneReason::Columns));
+            return Ok(false);
+        }
+    }
+    if let Some(units) = &search.units {
+        if !units_consistent(units, &equation.mapping, equation.base) {
+            trace!("pruned {:?}: units column doesn't add up", equation.mapping);
+            search.record(TraceEvent::Prune(PruneReason::Columns));
+            return Ok(false);
+        }
+    }
+    if equation
+        .constraints
+        .iter()
+        .any(|constraint| constraint.holds(&equation.mapping, equation.base) == Some(false))
+    {
+        trace!("pruned {:?}: a constraint fails", equation.mapping);
+        search.record(TraceEvent::Prune(PruneReason::Constraint));
+        return Ok(false);
+    }
+    if equation.unique_chars.is_empty() {
+        if !apply_operation(equation, &equation.mapping)? {
+            search.record(TraceEvent::Prune(PruneReason::Evaluation));
+            return Ok(false);
+        }
+        debug!("found solution {:?}", equation.mapping);
+        search.record(TraceEvent::Solution);
+        return Ok(on_solution(&equation.mapping));
+    }
+    // More letters than digits can never be assigned, so don't bother searching
+    if equation.unique_digits
+        && equation.unique_chars.len() + equation.mapping.len() > search.digits.len()
+    {
+        search.record(TraceEvent::Prune(PruneReason::TooFewDigits));
+        return Ok(false);
+    }
+
+    let ch = *search
+        .order
+        .iter()
+        .find(|ch| equation.unique_chars.contains(ch))
+        .expect("search order covers unique_chars");
+    equation.unique_chars.remove(&ch);
+    trace!("choosing {ch} at depth {}", equation.mapping.len());
+    for &digit in search.digits.iter() {
+        if let Some(reason) = assign_blocker(equation, ch, digit, used) {
+            search.record(TraceEvent::Prune(reason));
+            continue;
+        }
+        counters.assignments.fetch_add(1, Ordering::Relaxed);
+        search.record(TraceEvent::Enter { letter: ch, digit });
+        equation.mapping.insert(ch, digit);
+        if backtrack_from(equation, search, used | 1 << digit, on_solution)? {
+            return Ok(true);
+        }
+        equation.mapping.remove(&ch);
+        search.record(TraceEvent::Backtrack { letter: ch });
+    }
+    equation.unique_chars.insert(ch);
+    Ok(false)
+}
+
+fn number_token(digits: &[char]) -> Result<Token, CryptarithmError> {
+    let literal: String = digits.iter().collect();
+    literal
+        .parse()
+        .map(Token::Number)
+        .map_err(|_| CryptarithmError::Overflow(literal))
+}
+
+/// Parses an infix puzzle such as `SEND + MORE = MONEY`; either side of the `=` may be a
+/// full expression, e.g. `A * B = C + D`. `==` means the same as `=`, `<`, `<=`, `>` and
+/// `>=` work in place of it, and `A / B = C r D` states a division with quotient `C` and
+/// remainder `D` (a standalone `r` means this only after a division). Any other run of
+/// relation signs, such as `===`, is an error. Tabs and newlines separate tokens just
+/// like spaces, except between backticks: `` `SE ND` `` is the word `SEND`, and only
+/// letters, digits and whitespace may be quoted. A `-` with no left operand is a sign, so
+/// `A - B = -C` works; so is a `+` that opens a side or a parenthesis, but `A + + B` is
+/// an error.
+///
+/// A word is any run of `char::is_alphabetic` characters, so Greek, Cyrillic and other
+/// scripts work like ASCII. Letters are compared as plain `char`s: Latin `A` and Greek
+/// `Α` are different letters, as are `a` and `A` (see `parse_input_ignore_case`). Each
+/// letter needs a digit of its own, so a puzzle can use at most `base` distinct letters
+/// (10 unless changed with `Equation::in_base`); the solvers report
+/// `SolveError::TooManyLetters` past that.
+///
+/// Decimal digits inside a word are already-revealed digits, as in `S9ND + MOR5 = MONEY`:
+/// they keep their value in place, while the letters around them are solved as usual,
+/// free to reuse those digits. Digits with no letter among them are a plain number.
+///
+/// A plain addition whose result is too long or too short for any sum of its words, such
+/// as `A + B = CDE`, has no solution; `Equation::in_base` and `Equation::validate` report
+/// it as `CryptarithmError::ResultWidth`, and the solvers find nothing without searching.
+/// Parsing doesn't, since the widths depend on the base.
+///
+/// Only operators, parentheses and whitespace end a word, so a missing operator in
+/// `SENDMORE = MONEY` silently makes one long word; `SEND MORE = MONEY` is an error.
+/// `parse_input_strict` also rejects camel-cased joins like `SendMore = Money` and
+/// puzzles with more letters than the base has digits, but an all-caps join like
+/// `SENDMORE` still gets through.
+pub fn parse_input(expresion: &str) -> Result<Equation, CryptarithmError> {
+    parse_input_ignoring(expresion, &[])
+}
+
+/// Like `parse_input`, but drops any of `ignored` that sits between two letters, so with
+/// `&['\'', '-']` the words `DON'T` and `WELL-KNOWN` read as `DONT` and `WELLKNOWN`. A `-`
+/// with a space or a non-letter on either side is still a minus sign, but `A-B` becomes
+/// the word `AB` once `-` is ignored.
+pub fn parse_input_ignoring(
+    expresion: &str,
+    ignored: &[char],
+) -> Result<Equation, CryptarithmError> {
+    let mut equation = Equation::new();
+    let sides = parse_relation(expresion, ignored, &mut equation)?;
+    equation.rpn_token = sides.lhs;
+    equation.relation = sides.relation;
+    equation.result_token = sides.rhs;
+    equation.remainder_token = sides.remainder;
+    equation.check_tokens()?;
+    Ok(equation)
+}
+
+/// Rejects an addition like `A + B = CDE` whose result can't be as long as it is. Words
+/// can't start with zero, so each is at least `base^(len - 1)`; with `leading_zeros` any
+/// width goes.
+fn check_widths(equation: &Equation) -> Result<(), CryptarithmError> {
+    if equation.leading_zeros || addition_columns(equation).is_none() {
+        return Ok(());
+    }
+    let [Token::Word(result)] = &equation.result_token[..] else {
+        return Ok(());
+    };
+    let base = equation.base as u128;
+    let (mut lowest, mut highest) = (0u128, 0u128);
+    for token in equation.rpn_token.iter() {
+        if let Token::Word(word) = token {
+            // Sums too large for a u128 are too large to bother with here
+            let bounds = u32::try_from(word.len())
+                .ok()
+                .and_then(|len| base.checked_pow(len))
+                .and_then(|top| {
+                    Some((
+                        lowest.checked_add(top / base)?,
+                        highest.checked_add(top - 1)?,
+                    ))
+                });
+            let Some(bounds) = bounds else {
+                return Ok(());
+            };
+            (lowest, highest) = bounds;
+        }
+    }
+    let width = |mut value: u128| {
+        let mut digits = 1;
+        while value >= base {
+            value /= base;
+            digits += 1;
+        }
+        digits
+    };
+    let (min, max) = (width(lowest), width(highest));
+    if (min..=max).contains(&result.len()) {
+        Ok(())
+    } else {
+        Err(CryptarithmError::ResultWidth {
+            result: result.len(),
+            min,
+            max,
+        })
+    }
+}
+
+struct Sides {
+    lhs: Vec<Token>,
+    relation: Relation,
+    rhs: Vec<Token>,
+    remainder: Option<Vec<Token>>,
+}
+
+/// Splits `lhs <relation> rhs` and parses both sides, recording their letters on
+/// `equation`. When the left side is a division, a standalone `r` (or `R`, for
+/// `parse_input_ignore_case`) between two operands on the right starts a remainder, as in
+/// `A / B = C r D`; anywhere else `r` is just a letter.
+fn parse_relation(
+    expresion: &str,
+    ignored: &[char],
+    equation: &mut Equation,
+) -> Result<Sides, CryptarithmError> {
+    const RELATIONS: [char; 3] = ['<', '>', '='];
+    let Some(at) = expresion.find(RELATIONS) else {
+        return Err(CryptarithmError::MissingResult);
+    };
+    // Check the two-character relations first so `<=` isn't read as `<` then `=`
+    let (lhs, rest) = expresion.split_at(at);
+    let (relation, rhs) = [
+        ("<=", Relation::LessEqual),
+        (">=", Relation::GreaterEqual),
+        ("<", Relation::Less),
+        (">", Relation::Greater),
+        ("==", Relation::Equal),
+        ("=", Relation::Equal),
+    ]
+    .into_iter()
+    .find_map(|(symbol, relation)| Some((relation, rest.strip_prefix(symbol)?)))
+    .expect("rest starts with a relation character");
+    if rhs.contains(RELATIONS) {
+        return Err(CryptarithmError::InvalidExpression(
+            "more than one relation".to_string(),
+        ));
+    }
+
+    // Each side is a slice of `expresion`, so its start is where it sits in memory
+    let offset = |side: &str| {
+        let start = side.as_ptr() as usize - expresion.as_ptr() as usize;
+        expresion[..start].chars().count()
+    };
+    let lhs = parse_side(lhs, 0, ignored, equation)?;
+    let division = relation == Relation::Equal && matches!(lhs.last(), Some(Token::Operator('/')));
+    let (rhs, remainder) = if division {
+        split_remainder(rhs)
+    } else {
+        (rhs, None)
+    };
+    let rhs_tokens = parse_side(rhs, offset(rhs), ignored, equation)?;
+    if lhs.is_empty() {
+        return Err(CryptarithmError::InvalidExpression(
+            "missing expression before the relation".to_string(),
+        ));
+    }
+    if rhs_tokens.is_empty() {
+        return Err(CryptarithmError::MissingResult);
+    }
+    let remainder = match remainder {
+        Some(remainder) => {
+            let tokens = parse_side(remainder, offset(remainder), ignored, equation)?;
+            if tokens.is_empty() {
+                return Err(CryptarithmError::InvalidExpression(
+                    "a remainder needs the form X / Y = C r D".to_string(),
+                ));
+            }
+            Some(tokens)
+        }
+        None => None,
+    };
+    Ok(Sides {
+        lhs,
+        relation,
+        rhs: rhs_tokens,
+        remainder,
+    })
+}
+
+/// Splits `C r D` at the first `r` or `R` that isn't part of a longer word or quoted and
+/// has something on both sides of it.
+fn split_remainder(rhs: &str) -> (&str, Option<&str>) {
+    let chars = rhs.char_indices().collect_vec();
+    let mut quoted = false;
+    for (k, &(at, ch)) in chars.iter().enumerate() {
+        let before = k.checked_sub(1).map(|k| chars[k].1);
+        let after = chars.get(k + 1).map(|&(_, ch)| ch);
+        quoted ^= ch == QUOTE;
+        let (quotient, remainder) = (&rhs[..at], &rhs[at + 1..]);
+        if matches!(ch, 'r' | 'R')
+            && !quoted
+            && !before.is_some_and(char::is_alphanumeric)
+            && !after.is_some_and(char::is_alphanumeric)
+            && !quotient.trim().is_empty()
+            && !remainder.trim().is_empty()
+        {
+            return (quotient, Some(remainder));
+        }
+    }
+    (rhs, None)
+}
+
+/// Turns one side of the relation into RPN, recording its letters on `equation`.
+fn parse_side(
+    side: &str,
+    offset: usize,
+    ignored: &[char],
+    equation: &mut Equation,
+) -> Result<Vec<Token>, CryptarithmError> {
+    let chars = side.chars().collect_vec();
+    let mut rpn_token = vec![];
+    let mut buffer = vec![];
+    let mut operators = vec![];
+    let mut expect_operand = true;
+    // The operator still waiting for its right operand, with its position
+    let mut pending = None;
+    // The last operator or parenthesis, to tell a sign from a doubled operator
+    let mut previous = None;
+    // Where the open quote started, if there is one
+    let mut quote = None;
+    // The trailing space flushes an operand that ends the side
+    for (i, ch) in chars.iter().copied().chain([' ']).enumerate() {
+        let position = offset + i;
+        // Digits alone make a number, and with any letter among them a word
+        let word = buffer.iter().any(|c: &char| c.is_alphabetic());
+        let inside_word = word && chars.get(i + 1).is_some_and(|next| next.is_alphabetic());
+        if ch == QUOTE {
+            quote = match quote {
+                Some(_) => None,
+                None => Some(position),
+            };
+            continue;
+        } else if inside_word && ignored.contains(&ch) {
+            continue;
+        } else if ch.is_alphabetic() {
+            equation.unique_chars.insert(ch);
+            buffer.push(ch);
+            continue;
+        } else if ch.is_ascii_digit() {
+            buffer.push(ch);
+            continue;
+        } else if quote.is_some() {
+            if ch.is_ascii_whitespace() {
+                continue;
+            }
+            return Err(CryptarithmError::InvalidToken { ch, position });
+        } else if !is_operator(ch)
+            && ![FACTORIAL, '(', ')'].contains(&ch)
+            && !ch.is_ascii_whitespace()
+        {
+            return Err(CryptarithmError::InvalidToken { ch, position });
+        }
+
+        if !buffer.is_empty() && !expect_operand {
+            return Err(CryptarithmError::InvalidExpression(format!(
+                "missing operator before {}",
+                buffer.iter().collect::<String>()
+            )));
+        }
+        if word {
+            // A revealed leading digit is the puzzle's own choice, zero or not
+            if buffer[0].is_alphabetic() {
+                equation.special_chars.insert(buffer[0]);
+            }
+            rpn_token.push(Token::Word(buffer.split_off(0)));
+            expect_operand = false;
+            pending = None;
+        } else if !buffer.is_empty() {
+            rpn_token.push(number_token(&buffer.split_off(0))?);
+            expect_operand = false;
+            pending = None;
+        }
+
+        if ch == FACTORIAL {
+            if expect_operand {
+                return Err(CryptarithmError::MisplacedOperator { op: ch, position });
+            }
+            rpn_token.push(Token::Factorial);
+        } else if ch == '-' && expect_operand {
+            push_operator(&mut rpn_token, &mut operators, NEGATE);
+            pending = Some((ch, position));
+        } else if ch == '+' && expect_operand && matches!(previous, None | Some('(')) {
+            // Unary plus changes nothing, but still needs an operand after it
+            pending = Some((ch, position));
+        } else if is_operator(ch) {
+            if expect_operand {
+                return Err(CryptarithmError::MisplacedOperator { op: ch, position });
+            }
+            push_operator(&mut rpn_token, &mut operators, ch);
+            expect_operand = true;
+            pending = Some((ch, position));
+        } else if ch == '(' {
+            if !expect_operand {
+                return Err(CryptarithmError::InvalidExpression(format!(
+                    "missing operator before ( at position {}",
+                    position
+                )));
+            }
+            operators.push(ch);
+            expect_operand = true;
+        } else if ch == ')' {
+            if let Some((op, position)) = pending.filter(|_| expect_operand) {
+                return Err(CryptarithmError::MisplacedOperator { op, position });
+            }
+            expect_operand = false;
+            loop {
+                match operators.pop() {
+                    Some('(') => break,
+                    Some(op) => rpn_token.push(operator_token(op)),
+                    None => return Err(CryptarithmError::UnbalancedParens),
+                }
+            }
+        }
+        if !ch.is_ascii_whitespace() {
+            previous = Some(ch);
+        }
+    }
+    if let Some(position) = quote {
+        return Err(CryptarithmError::InvalidExpression(format!(
+            "unclosed {} at position {}",
+            QUOTE, position
+        )));
+    }
+    if let Some((op, position)) = pending {
+        return Err(CryptarithmError::MisplacedOperator { op, position });
+    }
+
+    while let Some(op) = operators.pop() {
+        if op == '(' {
+            return Err(CryptarithmError::UnbalancedParens);
+        }
+        rpn_token.push(operator_token(op));
+    }
+    Ok(rpn_token)
+}
+
+/// Like `parse_input`, but treats likely typos as errors instead of gluing words
+/// together: a word where an uppercase letter follows a lowercase one, as in
+/// `SendMore`, fails with `CryptarithmError::JoinedWords`, and a puzzle with more than 10
+/// distinct letters fails with `CryptarithmError::TooManyLetters` now rather than at
+/// solve time.
+pub fn parse_input_strict(expresion: &str) -> Result<Equation, CryptarithmError> {
+    let equation = parse_input(expresion)?;
+    for word in equation.words() {
+        let joined = word
+            .chars()
+            .tuple_windows()
+            .any(|(a, b)| a.is_lowercase() && b.is_uppercase());
+        if joined {
+            return Err(CryptarithmError::JoinedWords(word));
+        }
+    }
+    equation.check_letter_count()?;
+    Ok(equation)
+}
+
+/// Like `parse_input`, but uppercases every letter first so `send + more = money` and
+/// `SEND + MORE = MONEY` give the same `Equation`. A letter whose uppercase is more than
+/// one char, such as `ß`, stays as it is, so the puzzle keeps its letters and error
+/// positions still point into `expresion`.
+pub fn parse_input_ignore_case(expresion: &str) -> Result<Equation, CryptarithmError> {
+    let uppercased: String = expresion
+        .chars()
+        .map(|ch| {
+            let mut upper = ch.to_uppercase();
+            match (upper.next(), upper.next()) {
+                (Some(single), None) => single,
+                _ => ch,
+            }
+        })
+        .collect();
+    parse_input(&uppercased)
+}
+
+/// Like `parse_input`, but seeds the solver with letters whose digits are already known.
+pub fn parse_input_with(
+    expresion: &str,
+    fixed: &BTreeMap<char, u8>,
+) -> Result<Equation, CryptarithmError> {
+    let mut equation = parse_input(expresion)?;
+    for (&ch, &digit) in fixed {
+        if !equation.unique_chars.remove(&ch) {
+            return Err(CryptarithmError::UnknownLetter(ch));
+        }
+        if digit >= equation.base {
+            return Err(CryptarithmError::DigitOutOfRange {
+                letter: ch,
+                digit,
+                base: equation.base,
+            });
+        }
+        if digit == 0 && equation.special_chars.contains(&ch) {
+            return Err(CryptarithmError::LeadingZero(ch));
+        }
+        if let Some((other, _)) = equation.mapping.iter().find(|(_, &v)| v == digit) {
+            return Err(CryptarithmError::DuplicateDigit(*other, ch, digit));
+        }
+        equation.mapping.insert(ch, digit);
+    }
+    Ok(equation)
+}
+
+/// Parses `;`- or newline-separated equations that share one letter alphabet.
+pub fn parse_system(expresion: &str) -> Result<Vec<Equation>, CryptarithmError> {
+    let mut equations = expresion
+        .split([';', '\n'])
+        .filter(|part| !part.trim().is_empty())
+        .map(parse_input)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let unique_chars: BTreeSet<char> = equations
+        .iter()
+        .flat_map(|equation| equation.unique_chars.iter().copied())
+        .collect();
+    let special_chars: BTreeSet<char> = equations
+        .iter()
+        .flat_map(|equation| equation.special_chars.iter().copied())
+        .collect();
+    for equation in equations.iter_mut() {
+        equation.unique_chars = unique_chars.clone();
+        equation.special_chars = special_chars.clone();
+    }
+    Ok(equations)
+}
+
+/// Finds one mapping that satisfies every equation of a system from `parse_system`, on
+/// the calling thread. `Ok(None)` means there is none; an empty system is solved by the
+/// empty mapping. Gives up with `SolveError::Timeout` after `solve`'s usual timeout.
+pub fn solve_system(equations: &[Equation]) -> Result<Option<BTreeMap<char, u8>>, SolveError> {
+    let Some((first, rest)) = equations.split_first() else {
+        return Ok(Some(BTreeMap::new()));
+    };
+    first.check_solvable()?;
+
+    let mut equation = first.clone();
+    let mut solution = None;
+    let mut search = Search::new(&equation);
+    search.deadline = Instant::now().checked_add(DEFAULT_TIMEOUT);
+    backtrack(&mut equation, &search, &mut |mapping| {
+        if rest
+            .iter()
+            .all(|other| apply_operation(other, mapping).unwrap_or(false))
+        {
+            solution = Some(mapping.clone());
+            return true;
+        }
+        false
+    })
+    .unwrap_or_default();
+    match solution {
+        None if search.past_deadline() => Err(SolveError::Timeout),
+        solution => Ok(solution),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, thiserror::Error)]
+pub enum SolveError {
+    /// The deadline passed before the search finished.
+    #[error("Timed out before the search finished")]
+    Timeout,
+    /// The caller's cancellation flag was set.
+    #[error("Cancelled before the search finished")]
+    Cancelled,
+    /// The search visited as many nodes as `solve_with_budget` allowed.
+    #[error("Used up the node budget before the search finished")]
+    BudgetExhausted,
+    /// More distinct letters than the base has digits, so no bijection exists.
+    #[error("{letters} distinct letters cannot fit in base {base}")]
+    TooManyLetters { letters: usize, base: u8 },
+}
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(7);
+
+/// Returns `Ok(None)` when the search finished without finding a solution.
+///
+/// With the `cache` feature, results are memoized for two minutes keyed on the whole
+/// `Equation`, `mapping` included, so the same puzzle seeded with different letters
+/// gets its own entry.
+#[cfg(feature = "threads")]
+#[cfg_attr(feature = "cache", cached(size = 1024, time = 120, result = true))]
+pub fn solve(equation: Equation) -> Result<Option<BTreeMap<char, u8>>, SolveError> {
+    solve_with_timeout(equation, DEFAULT_TIMEOUT)
+}
+
+/// Drops every memoized `solve` result.
+#[cfg(all(feature = "cache", feature = "threads"))]
+pub fn clear_solve_cache() {
+    if let Ok(mut cache) = SOLVE.lock() {
+        cache.cache_clear();
+    }
+}
+
+/// How many `solve` results are memoized right now.
+#[cfg(all(feature = "cache", feature = "threads"))]
+pub fn solve_cache_len() -> usize {
+    SOLVE.lock().map(|cache| cache.cache_size()).unwrap_or(0)
+}
+
+/// A `solve` cache with its own capacity and lifespan, for when the fixed 1024 entries
+/// and two minutes behind `solve` don't fit.
+#[cfg(all(feature = "cache", feature = "threads"))]
+pub struct SolveCache {
+    cache: Mutex<TimedSizedCache<Equation, Option<BTreeMap<char, u8>>>>,
+}
+
+#[cfg(all(feature = "cache", feature = "threads"))]
+impl SolveCache {
+    /// Holds up to `size` results (at least one) for `lifespan`, in whole seconds.
+    pub fn new(size: usize, lifespan: Duration) -> Self {
+        SolveCache {
+            cache: Mutex::new(TimedSizedCache::with_size_and_lifespan(
+                size.max(1),
+                lifespan.as_secs(),
+            )),
+        }
+    }
+
+    /// Like `solve_ref`, answering from the cache when it can. Errors are not cached.
+    pub fn solve(&self, equation: &Equation) -> Result<Option<BTreeMap<char, u8>>, SolveError> {
+        if let Some(hit) = self.lock().cache_get(equation) {
+            return Ok(hit.clone());
+        }
+        // Not holding the lock while searching lets other threads use the cache meanwhile
+        let solution = solve_ref(equation)?;
+        self.lock().cache_set(equation.clone(), solution.clone());
+        Ok(solution)
+    }
+
+    pub fn clear(&self) {
+        self.lock().cache_clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().cache_size()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn lock(&self) -> MutexGuard<'_, TimedSizedCache<Equation, Option<BTreeMap<char, u8>>>> {
+        self.cache
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(feature = "threads")]
+struct Workers {
+    // Yields the first solution, or `None` once every worker has finished
+    tx: Receiver<Option<BTreeMap<char, u8>>>,
+    counters: Vec<Arc<Counters>>,
+}
+
+#[cfg(feature = "threads")]
+impl Workers {
+    /// Waits for the first solution until `deadline`, which the workers should have been
+    /// given too so they stop instead of outliving the call.
+    fn first_before(
+        &self,
+        deadline: Option<Instant>,
+    ) -> Result<Option<BTreeMap<char, u8>>, SolveError> {
+        let received = match deadline {
+            Some(deadline) => self
+                .tx
+                .recv_deadline(deadline)
+                .map_err(|_| SolveError::Timeout)?,
+            None => self.tx.recv().unwrap_or(None),
+        };
+        match received {
+            // Workers that ran out of time finish empty-handed just like an exhausted search
+            None if deadline.is_some_and(|deadline| Instant::now() >= deadline) => {
+                Err(SolveError::Timeout)
+            }
+            received => Ok(received),
+        }
+    }
+}
+
+#[cfg(feature = "threads")]
+#[derive(Default)]
+struct WorkerOptions {
+    cancel: Arc<AtomicBool>,
+    // `None` runs on rayon's global pool
+    threads: Option<usize>,
+    deadline: Option<Instant>,
+    // Digits the letters may take, `0..base` when `None`
+    digits: Option<Vec<u8>>,
+}
+
+/// Splits the search on the first letter's digit so rayon can explore those subtrees in
+/// parallel; with nothing left to assign there is a single branch that just checks. With
+/// `stop_together` every branch stops at the first solution any of them finds, otherwise
+/// each stops at its own.
+#[cfg(feature = "threads")]
+fn split_branches(
+    equation: &Equation,
+    digits: &[u8],
+    options: &WorkerOptions,
+    stop_together: bool,
+) -> Vec<(Equation, Search)> {
+    let first_assignments = match search_order(equation).first() {
+        Some(&ch) => digits
+            .iter()
+            .filter(|&&digit| can_assign(equation, ch, digit))
+            .map(|&digit| Some((ch, digit)))
+            .collect_vec(),
+        None => vec![None],
+    };
+    // Each branch gets its own copy of the equation to assign letters in, but shares the
+    // read-only parts of the search
+    let template = Search::new(equation);
+    let found = Arc::new(AtomicBool::new(false));
+    first_assignments
+        .into_iter()
+        .map(|assignment| {
+            let search = Search {
+                digits: digits.to_vec(),
+                order: template.order.clone(),
+                columns: template.columns.clone(),
+                units: template.units.clone(),
+                cancel: options.cancel.clone(),
+                found: if stop_together {
+                    found.clone()
+                } else {
+                    Arc::default()
+                },
+                counters: Arc::default(),
+                deadline: options.deadline,
+                budget: None,
+                trace: None,
+            };
+            let mut equation = equation.clone();
+            if let Some((ch, digit)) = assignment {
+                search.counters.assignments.fetch_add(1, Ordering::Relaxed);
+                equation.unique_chars.remove(&ch);
+                equation.mapping.insert(ch, digit);
+            }
+            (equation, search)
+        })
+        .collect_vec()
+}
+
+#[cfg(feature = "threads")]
+fn spawn_workers(equation: &Equation, options: &WorkerOptions) -> Result<Workers, SolveError> {
+    equation.check_solvable()?;
+    let (rx, tx) = crossbeam_channel::bounded(1);
+    let digits = match &options.digits {
+        Some(digits) => digits.clone(),
+        None => (0..equation.base).collect_vec(),
+    };
+    let branches = split_branches(equation, &digits, options, true);
+    let counters = branches
+        .iter()
+        .map(|(_, search)| search.counters.clone())
+        .collect();
+
+    let pool = options.threads.and_then(|threads| {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .ok()
+    });
+    thread::spawn(move || {
+        let run = || {
+            branches.into_par_iter().for_each(|(mut equation, search)| {
+                let stopped = backtrack(&mut equation, &search, &mut |mapping| {
+                    search.found.store(true, Ordering::Relaxed);
+                    rx.send(Some(mapping.clone())).unwrap_or(());
+                    true
+                });
+                // A branch cut short by a solution, the deadline or a cancel isn't done
+                if let Ok(false) = stopped {
+                    search.counters.finished.store(true, Ordering::Relaxed);
+                }
+            })
+        };
+        match pool {
+            Some(pool) => pool.install(run),
+            None => run(),
+        }
+        debug!("all search branches finished");
+        rx.send(None).unwrap_or_default();
+    });
+    Ok(Workers { tx, counters })
+}
+
+/// Like `solve`, but borrows the equation so a caller solving it in a loop doesn't have
+/// to clone it each time. Results are never cached.
+#[cfg(feature = "threads")]
+pub fn solve_ref(equation: &Equation) -> Result<Option<BTreeMap<char, u8>>, SolveError> {
+    solve_within(equation, DEFAULT_TIMEOUT)
+}
+
+/// Like `solve` with a custom deadline; `Duration::MAX` waits for the search to finish.
+#[cfg(feature = "threads")]
+pub fn solve_with_timeout(
+    equation: Equation,
+    timeout: Duration,
+) -> Result<Option<BTreeMap<char, u8>>, SolveError> {
+    solve_within(&equation, timeout)
+}
+
+#[cfg(feature = "threads")]
+fn solve_within(
+    equation: &Equation,
+    timeout: Duration,
+) -> Result<Option<BTreeMap<char, u8>>, SolveError> {
+    // Also hand the deadline to the workers so they stop instead of outliving the call
+    let deadline = Instant::now().checked_add(timeout);
+    let workers = spawn_workers(
+        equation,
+        &WorkerOptions {
+            deadline,
+            ..Default::default()
+        },
+    )?;
+    let result = workers.first_before(deadline);
+    debug!(
+        "solving {equation} gave {result:?} after {} nodes",
+        SolveStats::collect(&workers.counters).nodes_visited
+    );
+    result
+}
+
+/// Like `solve`, but never gives up: it waits however long the search takes, so `None`
+/// always means there is no solution.
+#[cfg(feature = "threads")]
+pub fn solve_exhaustive(equation: Equation) -> Option<BTreeMap<char, u8>> {
+    let workers = spawn_workers(&equation, &WorkerOptions::default()).ok()?;
+    // The driver thread sends `None` only after every branch has been searched
+    workers.tx.recv().ok().flatten()
+}
+
+/// Like `solve`, but the workers unwind as soon as `cancel` is set.
+#[cfg(feature = "threads")]
+pub fn solve_with_cancel(
+    equation: Equation,
+    cancel: Arc<AtomicBool>,
+) -> Result<Option<BTreeMap<char, u8>>, SolveError> {
+    let deadline = Instant::now().checked_add(DEFAULT_TIMEOUT);
+    let workers = spawn_workers(
+        &equation,
+        &WorkerOptions {
+            cancel: cancel.clone(),
+            deadline,
+            ..Default::default()
+        },
+    )?;
+    match workers.first_before(deadline) {
+        Ok(None) if cancel.load(Ordering::Relaxed) => Err(SolveError::Cancelled),
+        result => result,
+    }
+}
+
+/// Like `solve`, but searches the first letter's digits on a pool of `threads` workers.
+/// `threads == 1` searches them in ascending order, so the answer is deterministic.
+#[cfg(feature = "threads")]
+pub fn solve_with_threads(
+    equation: Equation,
+    threads: usize,
+) -> Result<Option<BTreeMap<char, u8>>, SolveError> {
+    let deadline = Instant::now().checked_add(DEFAULT_TIMEOUT);
+    spawn_workers(
+        &equation,
+        &WorkerOptions {
+            threads: Some(threads),
+            deadline,
+            ..Default::default()
+        },
+    )?
+    .first_before(deadline)
+}
+
+/// Like `solve`, but also returns the wall-clock time spent, up to the solution or the
+/// timeout.
+#[cfg(feature = "threads")]
+pub fn solve_timed(
+    equation: Equation,
+) -> (Result<Option<BTreeMap<char, u8>>, SolveError>, Duration) {
+    let started = Instant::now();
+    let result = solve_with_timeout(equation, DEFAULT_TIMEOUT);
+    (result, started.elapsed())
+}
+
+/// Solves many puzzles at once, one per rayon thread, returning the results in input
+/// order. Each puzzle is searched single-threaded like `solve_blocking` and gets the usual
+/// `solve` timeout from the moment its search starts.
+#[cfg(feature = "threads")]
+pub fn solve_batch(
+    equations: Vec<Equation>,
+) -> Vec<Result<Option<BTreeMap<char, u8>>, SolveError>> {
+    equations
+        .into_par_iter()
+        .map(|equation| solve_blocking_until(equation, Instant::now() + DEFAULT_TIMEOUT))
+        .collect()
+}
+
+/// Parses and solves one puzzle per line of `reader`, yielding each line with its outcome
+/// as it goes. Blank lines and lines starting with `#` are skipped, and each puzzle gets
+/// the usual `solve` timeout. A read error is yielded with an empty line and ends the
+/// iterator.
+#[cfg(feature = "threads")]
+pub fn solve_reader(
+    reader: impl BufRead,
+) -> impl Iterator<Item = (String, Result<Option<BTreeMap<char, u8>>, CryptarithmError>)> {
+    let mut lines = reader.lines();
+    let mut failed = false;
+    iter::from_fn(move || loop {
+        if failed {
+            return None;
+        }
+        let line = match lines.next()? {
+            Ok(line) => line,
+            Err(error) => {
+                failed = true;
+                return Some((
+                    String::new(),
+                    Err(CryptarithmError::Read(error.to_string())),
+                ));
+            }
+        };
+        let puzzle = line.trim();
+        if puzzle.is_empty() || puzzle.starts_with('#') {
+            continue;
+        }
+        let outcome = parse_input(puzzle).and_then(|equation| Ok(solve(equation)?));
+        return Some((line, outcome));
+    })
+}
+
+/// Like `solve`, but letters only take digits from `digits`; the leading-zero rule still
+/// applies on top. Digits outside the equation's base are ignored.
+#[cfg(feature = "threads")]
+pub fn solve_with_digits(
+    equation: Equation,
+    digits: &[u8],
+) -> Result<Option<BTreeMap<char, u8>>, SolveError> {
+    let digits = digits
+        .iter()
+        .copied()
+        .filter(|&digit| digit < equation.base)
+        .sorted()
+        .dedup()
+        .collect();
+    let deadline = Instant::now().checked_add(DEFAULT_TIMEOUT);
+    spawn_workers(
+        &equation,
+        &WorkerOptions {
+            digits: Some(digits),
+            deadline,
+            ..Default::default()
+        },
+    )?
+    .first_before(deadline)
+}
+
+#[cfg(feature = "threads")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SolveStats {
+    pub nodes_visited: u64,
+    /// Deepest level any worker is currently at, in assigned letters.
+    pub depth: usize,
+    pub assignments_tried: u64,
+    /// The search is split into one branch per digit the first letter can take; this
+    /// many of `branches` are done.
+    pub branches_done: usize,
+    pub branches: usize,
+}
+
+#[cfg(feature = "threads")]
+impl SolveStats {
+    fn collect(counters: &[Arc<Counters>]) -> Self {
+        let mut stats = SolveStats {
+            branches: counters.len(),
+            ..Default::default()
+        };
+        for counter in counters {
+            stats.nodes_visited += counter.nodes.load(Ordering::Relaxed);
+            stats.assignments_tried += counter.assignments.load(Ordering::Relaxed);
+            stats.depth = stats.depth.max(counter.depth.load(Ordering::Relaxed));
+            stats.branches_done += counter.finished.load(Ordering::Relaxed) as usize;
+        }
+        stats
+    }
+
+    /// Rough share of the search done so far, from 0 to 1, counted in whole branches;
+    /// branches can differ a lot in size.
+    pub fn fraction_done(&self) -> f32 {
+        if self.branches == 0 {
+            return 1.0;
+        }
+        self.branches_done as f32 / self.branches as f32
+    }
+}
+
+/// Like `solve`, but also returns the search statistics once every worker has stopped.
+/// The parallel branches race each other, so the totals vary a little between runs.
+#[cfg(feature = "threads")]
+pub fn solve_with_stats(
+    equation: Equation,
+) -> Result<(Option<BTreeMap<char, u8>>, SolveStats), SolveError> {
+    let deadline = Instant::now() + DEFAULT_TIMEOUT;
+    let workers = spawn_workers(
+        &equation,
+        &WorkerOptions {
+            deadline: Some(deadline),
+            ..Default::default()
+        },
+    )?;
+    let mut solution = None;
+    // Drain up to the sentinel so the counters are final
+    loop {
+        match workers.tx.recv_deadline(deadline) {
+            Ok(Some(mapping)) => solution = solution.or(Some(mapping)),
+            Ok(None) => break,
+            Err(_) => return Err(SolveError::Timeout),
+        }
+    }
+    if solution.is_none() && Instant::now() >= deadline {
+        return Err(SolveError::Timeout);
+    }
+    Ok((solution, SolveStats::collect(&workers.counters)))
+}
+
+/// Like `solve_with_stats`, but tries digits in an order shuffled by `seed`, so different
+/// seeds can turn up different solutions of the same puzzle. The branches don't race:
+/// each searches up to its own first solution and the earliest branch's wins, so the
+/// same seed and equation always give the same solution and statistics, at the cost of
+/// some extra work.
+#[cfg(feature = "threads")]
+pub fn solve_seeded(
+    equation: Equation,
+    seed: u64,
+) -> Result<(Option<BTreeMap<char, u8>>, SolveStats), SolveError> {
+    equation.check_solvable()?;
+    let mut rng = SplitMix(seed);
+    let mut digits = (0..equation.base).collect_vec();
+    for i in (1..digits.len()).rev() {
+        digits.swap(i, rng.below(i as u64 + 1) as usize);
+    }
+    let options = WorkerOptions {
+        deadline: Instant::now().checked_add(DEFAULT_TIMEOUT),
+        ..Default::default()
+    };
+    let branches = split_branches(&equation, &digits, &options, false);
+    let counters = branches
+        .iter()
+        .map(|(_, search)| search.counters.clone())
+        .collect_vec();
+    let outcomes: Vec<_> = branches
+        .into_par_iter()
+        .map(|(equation, search)| {
+            let solution = first_solution(equation, &search);
+            let timed_out = solution.is_none() && search.past_deadline();
+            // Only a branch searched to the end counts as done
+            if solution.is_none() && !timed_out && !search.stopped() {
+                search.counters.finished.store(true, Ordering::Relaxed);
+            }
+            (timed_out, solution)
+        })
+        .collect();
+    // A branch that ran out of time might have held the winning solution
+    match outcomes
+        .into_iter()
+        .find(|(timed_out, solution)| *timed_out || solution.is_some())
+    {
+        Some((true, _)) => Err(SolveError::Timeout),
+        found => Ok((
+            found.and_then(|(_, solution)| solution),
+            SolveStats::collect(&counters),
+        )),
+    }
+}
+
+#[cfg(feature = "threads")]
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Like `solve`, reporting search statistics every 100ms and once more when it returns.
+#[cfg(feature = "threads")]
+pub fn solve_with_progress(
+    equation: Equation,
+    mut callback: impl FnMut(SolveStats),
+) -> Result<Option<BTreeMap<char, u8>>, SolveError> {
+    let deadline = Instant::now() + DEFAULT_TIMEOUT;
+    let workers = spawn_workers(
+        &equation,
+        &WorkerOptions {
+            deadline: Some(deadline),
+            ..Default::default()
+        },
+    )?;
+    loop {
+        let wait = deadline
+            .saturating_duration_since(Instant::now())
+            .min(PROGRESS_INTERVAL);
+        let received = workers.tx.recv_timeout(wait);
+        callback(SolveStats::collect(&workers.counters));
+        match received {
+            // Workers that ran out of time finish empty-handed just like an exhausted search
+            Ok(None) if Instant::now() >= deadline => return Err(SolveError::Timeout),
+            Ok(result) => return Ok(result),
+            Err(RecvTimeoutError::Timeout) if Instant::now() < deadline => continue,
+            Err(_) => return Err(SolveError::Timeout),
+        }
+    }
+}
+
+/// Runs the search on the calling thread without spawning anything, for targets such as
+/// `wasm32-unknown-unknown` that have no threads. Returns `None` when there is no solution.
+///
+/// Letters are assigned in a fixed order and digits tried in ascending order, so the
+/// answer is the same solution run after run, though not necessarily the first in
+/// `BTreeMap` order; `solve_deterministic` gives that one.
+pub fn solve_blocking(equation: Equation) -> Option<BTreeMap<char, u8>> {
+    let search = Search::new(&equation);
+    first_solution(equation, &search)
+}
+
+/// Like `solve_blocking`, but assigns letters in `char` order, so the answer is the
+/// lexicographically first solution, the same as `solve_all(equation).first()`. The
+/// fixed order prunes later than the usual one, so this can be slower.
+pub fn solve_deterministic(equation: Equation) -> Option<BTreeMap<char, u8>> {
+    let mut search = Search::new(&equation);
+    search.order = equation.unique_chars.iter().copied().collect();
+    first_solution(equation, &search)
+}
+
+/// Like `solve_blocking`, but gives up with `SolveError::Timeout` once `deadline` passes.
+pub fn solve_blocking_until(
+    equation: Equation,
+    deadline: Instant,
+) -> Result<Option<BTreeMap<char, u8>>, SolveError> {
+    equation.check_solvable()?;
+    let mut search = Search::new(&equation);
+    search.deadline = Some(deadline);
+    match first_solution(equation, &search) {
+        None if search.past_deadline() => Err(SolveError::Timeout),
+        solution => Ok(solution),
+    }
+}
+
+/// Like `solve_blocking`, but gives up with `SolveError::BudgetExhausted` after visiting
+/// `max_nodes` nodes of the search tree. Unlike a timeout, this stops at the same point on
+/// every machine.
+pub fn solve_with_budget(
+    equation: Equation,
+    max_nodes: u64,
+) -> Result<Option<BTreeMap<char, u8>>, SolveError> {
+    equation.check_solvable()?;
+    let mut search = Search::new(&equation);
+    search.budget = Some(max_nodes);
+    match first_solution(equation, &search) {
+        // The node that hit the budget is counted too
+        None if search.counters.nodes.load(Ordering::Relaxed) > max_nodes => {
+            Err(SolveError::BudgetExhausted)
+        }
+        solution => Ok(solution),
+    }
+}
+
+/// Like `solve_blocking`, but also returns every step the search took on the way.
+pub fn solve_traced(equation: Equation) -> (Option<BTreeMap<char, u8>>, Vec<TraceEvent>) {
+    let mut search = Search::new(&equation);
+    search.trace = Some(RefCell::default());
+    let solution = first_solution(equation, &search);
+    (solution, search.trace.unwrap_or_default().into_inner())
+}
+
+fn first_solution(mut equation: Equation, search: &Search) -> Option<BTreeMap<char, u8>> {
+    let mut solution = None;
+    backtrack(&mut equation, search, &mut |mapping| {
+        solution = Some(mapping.clone());
+        true
+    })
+    .unwrap_or_default();
+    debug!(
+        "solving {equation} gave {solution:?} after {} nodes",
+        search.counters.nodes.load(Ordering::Relaxed)
+    );
+    solution
+}
+
+/// Every solution, sorted by `BTreeMap`'s own ordering so the list never depends on how
+/// the search got there.
+pub fn solve_all(mut equation: Equation) -> Vec<BTreeMap<char, u8>> {
+    let mut solutions = vec![];
+    let search = Search::new(&equation);
+    backtrack(&mut equation, &search, &mut |mapping| {
+        solutions.push(mapping.clone());
+        false
+    })
+    .unwrap_or_default();
+    solutions.sort_unstable();
+    solutions
+}
+
+/// How `solve_all_sorted` orders its solutions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SolutionOrder {
+    /// `BTreeMap`'s ordering, the same as `solve_all`.
+    #[default]
+    Mapping,
+    /// Smallest right-hand side first, ties in `Mapping` order.
+    Result,
+}
+
+/// Like `solve_all` in the order picked by `order`. Sorting by result fails only when a
+/// result is past i64, as in `solved_values`.
+pub fn solve_all_sorted(
+    equation: Equation,
+    order: SolutionOrder,
+) -> Result<Vec<BTreeMap<char, u8>>, CryptarithmError> {
+    let solutions = solve_all(equation.clone());
+    match order {
+        SolutionOrder::Mapping => Ok(solutions),
+        SolutionOrder::Result => {
+            let mut keyed = solutions
+                .into_iter()
+                .map(|mapping| Ok((solved_values(&equation, &mapping)?.1, mapping)))
+                .collect::<Result<Vec<_>, CryptarithmError>>()?;
+            // Already in mapping order, so a stable sort keeps ties that way
+            keyed.sort_by_key(|(result, _)| *result);
+            Ok(keyed.into_iter().map(|(_, mapping)| mapping).collect())
+        }
+    }
+}
+
+/// The search behind every solver, on the calling thread: hands each solution to
+/// `visitor` until it returns `true`, trying the digits of `digits` in the order given
+/// (ones outside the base are skipped). Returns whether `visitor` stopped the search;
+/// `equation` is left as it was either way.
+pub fn visit_solutions<F>(
+    equation: &mut Equation,
+    digits: &[u8],
+    mut visitor: F,
+) -> Result<bool, CryptarithmError>
+where
+    F: FnMut(&BTreeMap<char, u8>) -> bool,
+{
+    let mut search = Search::new(equation);
+    search.digits = digits
+        .iter()
+        .copied()
+        .filter(|&digit| digit < equation.base)
+        .unique()
+        .collect();
+    let (mapping, unique_chars) = (equation.mapping.clone(), equation.unique_chars.clone());
+    let stopped = backtrack(equation, &search, &mut visitor);
+    // Stopping early leaves the search's partial assignment behind
+    (equation.mapping, equation.unique_chars) = (mapping, unique_chars);
+    stopped
+}
+
+const DIFFICULTY_BUDGET: u64 = 100_000;
+
+/// A rough difficulty score, higher for harder puzzles and only meaningful next to other
+/// scores. It adds up the letters, the operators (`+` cheapest, `^` and `!` dearest) and
+/// the doublings in the size of the search tree, explored up to 100000 nodes, so the
+/// same equation always gets the same score.
+pub fn difficulty(equation: &Equation) -> u32 {
+    let mut search = Search::new(equation);
+    search.budget = Some(DIFFICULTY_BUDGET);
+    backtrack(&mut equation.clone(), &search, &mut |_| false).unwrap_or_default();
+    let nodes = search.counters.nodes.load(Ordering::Relaxed).max(1);
+    let doublings = nodes.ilog2() + 1;
+    let operators: u32 = equation
+        .tokens()
+        .chain(
+            equation
+                .constraints
+                .iter()
+                .flat_map(|c| c.lhs.iter().chain(c.rhs.iter())),
+        )
+        .map(|token| match token {
+            Token::Operator('+') | Token::Negate => 1,
+            Token::Operator('-') => 2,
+            Token::Operator('*') => 3,
+            Token::Operator('/' | '%') => 4,
+            Token::Operator('^') | Token::Factorial => 5,
+            Token::Operator(_) => 3,
+            Token::Word(_) | Token::Number(_) => 0,
+        })
+        .sum();
+    equation.unique_chars.len() as u32 * 2 + doublings * 3 + operators
+}
+
+/// Letters that take different digits in different solutions, i.e. the ones worth
+/// constraining to make a puzzle unique. Empty when there are fewer than two solutions.
+pub fn free_letters(equation: Equation) -> BTreeSet<char> {
+    let solutions = solve_all(equation);
+    let Some((first, rest)) = solutions.split_first() else {
+        return BTreeSet::new();
+    };
+    first
+        .iter()
+        .filter(|(ch, digit)| rest.iter().any(|other| other.get(ch) != Some(digit)))
+        .map(|(&ch, _)| ch)
+        .collect()
+}
+
+/// What `solve_optimal` ranks solutions by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Objective {
+    /// The smallest value of the right-hand side.
+    MinResult,
+    /// The largest value of the right-hand side.
+    MaxResult,
+    /// The smallest digit for this letter.
+    MinLetter(char),
+    /// The largest digit for this letter.
+    MaxLetter(char),
+}
+
+impl Objective {
+    fn maximize(self) -> bool {
+        matches!(self, Objective::MaxResult | Objective::MaxLetter(_))
+    }
+
+    fn score(
+        self,
+        equation: &Equation,
+        mapping: &BTreeMap<char, u8>,
+    ) -> Result<i64, CryptarithmError> {
+        match self {
+            Objective::MinLetter(ch) | Objective::MaxLetter(ch) => Ok(mapping[&ch] as i64),
+            Objective::MinResult | Objective::MaxResult => {
+                solved_values(equation, mapping).map(|(_, result)| result)
+            }
+        }
+    }
+}
+
+/// The solution that does best on `objective`, or `None` if there is none; ties are
+/// broken the same way every run. Rather than listing every solution, this tries the
+/// letter's digits (or, for a one-word result, its leading letter's) from best to worst
+/// and stops at the first that has a solution. A letter not in the equation is an error.
+pub fn solve_optimal(
+    equation: Equation,
+    objective: Objective,
+) -> Result<Option<BTreeMap<char, u8>>, CryptarithmError> {
+    let pivot = match objective {
+        Objective::MinLetter(ch) | Objective::MaxLetter(ch) => {
+            if !equation.unique_chars.contains(&ch) && !equation.mapping.contains_key(&ch) {
+                return Err(CryptarithmError::UnknownLetter(ch));
+            }
+            Some(ch)
+        }
+        Objective::MinResult | Objective::MaxResult => match &equation.result_token[..] {
+            [Token::Word(word)] => word.first().copied(),
+            _ => None,
+        },
+    };
+    // A letter fixed by the caller leaves nothing to choose
+    let Some(pivot) = pivot.filter(|ch| equation.unique_chars.contains(ch)) else {
+        return best_solution(equation, objective);
+    };
+    let mut digits = (0..equation.base).collect_vec();
+    if objective.maximize() {
+        digits.reverse();
+    }
+    for digit in digits {
+        if !can_assign(&equation, pivot, digit) {
+            continue;
+        }
+        let mut fixed = equation.clone();
+        fixed.unique_chars.remove(&pivot);
+        fixed.mapping.insert(pivot, digit);
+        let best = match objective {
+            // Every solution gives the letter the same digit now
+            Objective::MinLetter(_) | Objective::MaxLetter(_) => {
+                let search = Search::new(&fixed);
+                first_solution(fixed, &search)
+            }
+            Objective::MinResult | Objective::MaxResult => best_solution(fixed, objective)?,
+        };
+        if best.is_some() {
+            return Ok(best);
+        }
+    }
+    Ok(None)
+}
+
+fn best_solution(
+    equation: Equation,
+    objective: Objective,
+) -> Result<Option<BTreeMap<char, u8>>, CryptarithmError> {
+    let mut best: Option<(i64, BTreeMap<char, u8>)> = None;
+    for mapping in solve_all(equation.clone()) {
+        let score = objective.score(&equation, &mapping)?;
+        let better = best.as_ref().is_none_or(|(top, _)| {
+            if objective.maximize() {
+                score > *top
+            } else {
+                score < *top
+            }
+        });
+        if better {
+            best = Some((score, mapping));
+        }
+    }
+    Ok(best.map(|(_, mapping)| mapping))
+}
+
+pub fn count_solutions(mut equation: Equation) -> usize {
+    let mut count = 0;
+    let search = Search::new(&equation);
+    backtrack(&mut equation, &search, &mut |_| {
+        count += 1;
+        false
+    })
+    .unwrap_or_default();
+    count
+}
+
+/// Stops searching as soon as a second solution turns up.
+pub fn has_unique_solution(equation: Equation) -> bool {
+    matches!(verify_unique(equation), UniqueResult::Unique(_))
+}
+
+/// What `verify_unique` found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UniqueResult {
+    /// The only solution.
+    Unique(BTreeMap<char, u8>),
+    /// The first two solutions in search order, so the puzzle's author can see how the
+    /// second one differs.
+    Multiple(BTreeMap<char, u8>, BTreeMap<char, u8>),
+    None,
+}
+
+/// Like `has_unique_solution`, but hands back the solutions it found.
+pub fn verify_unique(mut equation: Equation) -> UniqueResult {
+    let mut solutions = vec![];
+    let search = Search::new(&equation);
+    backtrack(&mut equation, &search, &mut |mapping| {
+        solutions.push(mapping.clone());
+        solutions.len() > 1
+    })
+    .unwrap_or_default();
+    let mut solutions = solutions.into_iter();
+    match (solutions.next(), solutions.next()) {
+        (Some(first), Some(second)) => UniqueResult::Multiple(first, second),
+        (Some(only), None) => UniqueResult::Unique(only),
+        _ => UniqueResult::None,
+    }
+}
+
+/// splitmix64; puzzles need variety, not cryptographic randomness.
+struct SplitMix(u64);
+
+impl SplitMix {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+const GENERATE_ATTEMPTS: usize = 200;
+
+/// Like `generate_seeded`, with a seed that differs from call to call.
+pub fn generate(
+    word_count: usize,
+    max_len: usize,
+    base: u8,
+    unique: bool,
+) -> Result<(Equation, BTreeMap<char, u8>), CryptarithmError> {
+    use std::collections::hash_map::RandomState;
+    use std::hash::BuildHasher;
+    let seed = RandomState::new().hash_one(());
+    generate_seeded(word_count, max_len, base, unique, seed)
+}
+
+/// Makes up an addition of `word_count` words, the first `max_len` letters long and the
+/// rest 1 to `max_len`, returning
+/// the puzzle with an empty `mapping` and a solution to it. Letters are `A` to `Z`, so
+/// `base` can be at most 26. With `unique` set, puzzles are drawn until one has exactly
+/// one solution, giving up with `CryptarithmError::NoUniquePuzzle` after 200 tries.
+/// The same seed always gives the same puzzle.
+pub fn generate_seeded(
+    word_count: usize,
+    max_len: usize,
+    base: u8,
+    unique: bool,
+    seed: u64,
+) -> Result<(Equation, BTreeMap<char, u8>), CryptarithmError> {
+    if !(2..=26).contains(&base) {
+        return Err(CryptarithmError::UnsupportedBase(base));
+    }
+    if word_count < 2 || max_len == 0 {
+        return Err(CryptarithmError::InvalidExpression(
+            "a generated puzzle needs at least two words of at least one letter".to_string(),
+        ));
+    }
+    // Evaluation happens in i64, so the largest possible sum has to fit
+    let largest = u32::try_from(max_len)
+        .ok()
+        .and_then(|len| (base as i64).checked_pow(len))
+        .and_then(|limit| limit.checked_mul(word_count as i64));
+    if largest.is_none() {
+        return Err(CryptarithmError::InvalidExpression(format!(
+            "{} words of {} letters overflow in base {}",
+            word_count, max_len, base
+        )));
+    }
+
+    let mut rng = SplitMix(seed);
+    for _ in 0..GENERATE_ATTEMPTS {
+        let (equation, solution) = random_addition(&mut rng, word_count, max_len, base)?;
+        if !unique || has_unique_solution(equation.clone()) {
+            return Ok((equation, solution));
+        }
+    }
+    Err(CryptarithmError::NoUniquePuzzle(GENERATE_ATTEMPTS))
+}
+
+/// Builds the puzzle `mapping` answers: `shape` is the left-hand side, e.g. `SEND + MORE`,
+/// and the result is its value spelled back in `mapping`'s letters, base 10. Fails when
+/// `shape` uses a letter `mapping` lacks, comes out negative, or its value has a digit no
+/// letter stands for, and when the answer would break the leading-zero or distinct-digit
+/// rules.
+pub fn puzzle_from_solution(
+    mapping: &BTreeMap<char, u8>,
+    shape: &str,
+) -> Result<Equation, CryptarithmError> {
+    let mut equation = Equation::new();
+    equation.rpn_token = parse_side(shape, 0, &[], &mut equation)?;
+    equation.check_mapping(mapping)?;
+    let value = match eval_rpn(&equation.rpn_token, mapping, equation.base)? {
+        Some(value) if value >= Value::from(0) => value,
+        _ => {
+            return Err(CryptarithmError::InvalidExpression(format!(
+                "{} has no value of at least 0 under the mapping",
+                shape.trim()
+            )))
+        }
+    };
+    let letters = invert_mapping(mapping);
+    let result = format_value(&value, equation.base)
+        .chars()
+        .map(|digit| {
+            let digit = digit.to_digit(equation.base as u32).unwrap() as u8;
+            letters.get(&digit).copied().ok_or_else(|| {
+                CryptarithmError::InvalidExpression(format!(
+                    "no letter stands for digit {} of the result {}",
+                    digit, value
+                ))
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    equation.unique_chars.extend(result.iter());
+    equation.special_chars.insert(result[0]);
+    equation.result_token = vec![Token::Word(result)];
+
+    if let Some(&ch) = equation.special_chars.iter().find(|ch| mapping[ch] == 0) {
+        return Err(CryptarithmError::LeadingZero(ch));
+    }
+    if let Some((&a, &b)) = equation
+        .unique_chars
+        .iter()
+        .tuple_combinations()
+        .find(|(a, b)| mapping[a] == mapping[b])
+    {
+        return Err(CryptarithmError::DuplicateDigit(a, b, mapping[&a]));
+    }
+    Ok(equation)
+}
+
+fn random_addition(
+    rng: &mut SplitMix,
+    word_count: usize,
+    max_len: usize,
+    base: u8,
+) -> Result<(Equation, BTreeMap<char, u8>), CryptarithmError> {
+    // letters[digit] spells that digit
+    let mut letters = ('A'..='Z').collect_vec();
+    for i in (1..letters.len()).rev() {
+        letters.swap(i, rng.below(i as u64 + 1) as usize);
+    }
+    let radix = base as u64;
+    let numbers = (0..word_count)
+        .map(|i| {
+            let len = if i == 0 {
+                max_len as u64
+            } else {
+                1 + rng.below(max_len as u64)
+            };
+            // No leading zeros, including one-letter words
+            let first = 1 + rng.below(radix - 1);
+            (1..len).fold(first, |value, _| value * radix + rng.below(radix))
+        })
+        .collect_vec();
+    let spell = |value: u64| -> String {
+        format_digits(value, base)
+            .chars()
+            .map(|digit| letters[digit.to_digit(base as u32).unwrap() as usize])
+            .collect()
+    };
+    let puzzle = format!(
+        "{} = {}",
+        numbers.iter().map(|&value| spell(value)).join(" + "),
+        spell(numbers.iter().sum())
+    );
+    let equation = parse_input(&puzzle)?.in_base(base)?;
+    let solution = equation
+        .unique_chars
+        .iter()
+        .map(|&ch| (ch, letters.iter().position(|&l| l == ch).unwrap() as u8))
+        .collect();
+    Ok((equation, solution))
+}
+
+#[cfg(feature = "threads")]
+struct SolutionIter {
+    tx: Receiver<Result<BTreeMap<char, u8>, SolveError>>,
+    cancel: Arc<AtomicBool>,
+}
+
+#[cfg(feature = "threads")]
+impl Iterator for SolutionIter {
+    type Item = Result<BTreeMap<char, u8>, SolveError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.tx.recv().ok()
+    }
+}
+
+#[cfg(feature = "threads")]
+impl Drop for SolutionIter {
+    fn drop(&mut self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Streams solutions as the search finds them. Dropping the iterator stops the worker.
+/// The stream simply ends once every solution has been found; if the search is still
+/// going after `solve`'s timeout it ends with `Err(SolveError::Timeout)` instead, and a
+/// puzzle with too many letters yields only `Err(SolveError::TooManyLetters)`.
+#[cfg(feature = "threads")]
+pub fn solve_iter(
+    equation: Equation,
+) -> impl Iterator<Item = Result<BTreeMap<char, u8>, SolveError>> {
+    solve_iter_with_timeout(equation, DEFAULT_TIMEOUT)
+}
+
+/// Like `solve_iter` with a custom deadline, counted from this call and including the
+/// time spent between items; `Duration::MAX` never times out.
+#[cfg(feature = "threads")]
+pub fn solve_iter_with_timeout(
+    mut equation: Equation,
+    timeout: Duration,
+) -> impl Iterator<Item = Result<BTreeMap<char, u8>, SolveError>> {
+    let (rx, tx) = crossbeam_channel::bounded(1);
+    let mut search = Search::new(&equation);
+    search.deadline = Instant::now().checked_add(timeout);
+
+    let cancel = search.cancel.clone();
+    thread::spawn(move || {
+        if let Err(error) = equation.check_solvable() {
+            rx.send(Err(error)).unwrap_or_default();
+            return;
+        }
+        let stopped = backtrack(&mut equation, &search, &mut |mapping| {
+            rx.send(Ok(mapping.clone())).is_err()
+        })
+        .unwrap_or_default();
+        if stopped && search.past_deadline() {
+            rx.send(Err(SolveError::Timeout)).unwrap_or_default();
+        }
+    });
+
+    SolutionIter { tx, cancel }
+}
+
+/// Builds an `Equation` from words and operators without going through `parse_input`.
+#[derive(Debug, Clone, Default)]
+pub struct EquationBuilder {
+    terms: Vec<Token>,
+    result: Vec<char>,
+}
+
+impl EquationBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn word(mut self, word: &str) -> Self {
+        self.terms.push(Token::Word(word.chars().collect()));
+        self
+    }
+
+    pub fn number(mut self, value: u64) -> Self {
+        self.terms.push(Token::Number(value));
+        self
+    }
+
+    pub fn op(mut self, op: char) -> Self {
+        self.terms.push(Token::Operator(op));
+        self
+    }
+
+    pub fn result(mut self, word: &str) -> Self {
+        self.result = word.chars().collect();
+        self
+    }
+
+    pub fn build(self) -> Result<Equation, CryptarithmError> {
+        fn check_word(word: &[char]) -> Result<(), CryptarithmError> {
+            if word.is_empty() {
+                return Err(CryptarithmError::InvalidExpression(
+                    "empty word".to_string(),
+                ));
+            }
+            if let Some((position, &ch)) = word.iter().find_position(|c| !c.is_alphabetic()) {
+                return Err(CryptarithmError::InvalidToken { ch, position });
+            }
+            Ok(())
+        }
+
+        let mut equation = Equation::new();
+        let mut operators = vec![];
+        let mut expect_word = true;
+        for term in self.terms {
+            match term {
+                Token::Word(word) => {
+                    if !expect_word {
+                        return Err(CryptarithmError::InvalidExpression(format!(
+                            "missing operator before {}",
+                            word.iter().join("")
+                        )));
+                    }
+                    check_word(&word)?;
+                    equation.unique_chars.extend(word.iter());
+                    equation.special_chars.insert(word[0]);
+                    equation.rpn_token.push(Token::Word(word));
+                }
+                Token::Number(value) => {
+                    if !expect_word {
+                        return Err(CryptarithmError::InvalidExpression(format!(
+                            "missing operator before {}",
+                            value
+                        )));
+                    }
+                    equation.rpn_token.push(Token::Number(value));
+                }
+                Token::Operator('-') | Token::Negate if expect_word => {
+                    push_operator(&mut equation.rpn_token, &mut operators, NEGATE);
+                    continue;
+                }
+                Token::Operator(FACTORIAL) | Token::Factorial if !expect_word => {
+                    equation.rpn_token.push(Token::Factorial);
+                    continue;
+                }
+                Token::Factorial => {
+                    return Err(CryptarithmError::InvalidExpression(
+                        "missing word before operator !".to_string(),
+                    ))
+                }
+                Token::Negate => {
+                    return Err(CryptarithmError::InvalidExpression(
+                        "missing word before operator -".to_string(),
+                    ))
+                }
+                Token::Operator(op) => {
+                    if expect_word {
+                        return Err(CryptarithmError::InvalidExpression(format!(
+                            "missing word before operator {}",
+                            op
+                        )));
+                    }
+                    if !is_operator(op) {
+                        return Err(CryptarithmError::InvalidOperator(op));
+                    }
+                    push_operator(&mut equation.rpn_token, &mut operators, op);
+                }
+            }
+            expect_word = !expect_word;
+        }
+        if expect_word {
+            return Err(CryptarithmError::InvalidExpression(
+                "expression must end with a word".to_string(),
+            ));
+        }
+        while let Some(op) = operators.pop() {
+            equation.rpn_token.push(operator_token(op));
+        }
+
+        check_word(&self.result)?;
+        equation.unique_chars.extend(self.result.iter());
+        equation.special_chars.insert(self.result[0]);
+        equation.result_token = vec![Token::Word(self.result)];
+
+        equation.check_tokens()?;
+        Ok(equation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lowercase_r_is_a_letter_outside_a_division() {
+        let equation = parse_input("a + b = r").unwrap();
+        assert_eq!(equation.words(), ["a", "b", "r"]);
+    }
+
+    #[test]
+    fn remainder_words_are_listed() {
+        let equation = parse_input("AB / C = D r E").unwrap();
+        assert_eq!(equation.words(), ["AB", "C", "D", "E"]);
+        let equation = parse_input_ignore_case("ab / c = d r e").unwrap();
+        assert_eq!(equation.to_string(), "AB / C = D r E");
+    }
+
+    #[cfg(feature = "bigint")]
+    #[test]
+    fn huge_products_reject_the_candidate() {
+        assert!(factorial(Value::from(987654)).is_none());
+        assert_eq!(factorial(Value::from(5)), Some(Value::from(120)));
+        let power = binary_operator('^').unwrap();
+        assert!(apply_binary('^', power, Value::from(987654), Value::from(9876)).is_none());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn deserializing_checks_the_equation() {
+        let json = serde_json::to_string(&parse_input("SEND + MORE = MONEY").unwrap()).unwrap();
+        assert!(serde_json::from_str::<Equation>(&json).is_ok());
+        let json = json.replace(r#""base":10"#, r#""base":100"#);
+        assert!(serde_json::from_str::<Equation>(&json).is_err());
+    }
+
+    #[test]
+    fn solve_deterministic_gives_the_first_solution() {
+        for puzzle in ["AB + CD = EF", "XY + Z = AB", "AB + C = DE"] {
+            let equation = parse_input(puzzle).unwrap();
+            assert_eq!(
+                solve_deterministic(equation.clone()).as_ref(),
+                solve_all(equation).first()
+            );
+        }
+    }
+
+    #[test]
+    fn solve_system_tells_no_solution_from_no_letters() {
+        let holds = parse_system("1 + 1 = 2").unwrap();
+        assert_eq!(solve_system(&holds), Ok(Some(BTreeMap::new())));
+        let fails = parse_system("1 + 1 = 3").unwrap();
+        assert_eq!(solve_system(&fails), Ok(None));
+        let system = parse_system("A + B = C; A * B = C + A").unwrap();
+        let solution = solve_system(&system).unwrap().unwrap();
+        assert!(system
+            .iter()
+            .all(|equation| evaluate(equation, &solution).unwrap()));
+    }
+
+    #[test]
+    fn result_width_uses_the_real_base() {
+        let equation = parse_input("A + A + A + A = ABB").unwrap();
+        assert!(matches!(
+            equation.validate(),
+            Err(CryptarithmError::ResultWidth { .. })
+        ));
+        assert_eq!(solve_all(equation.in_base(2).unwrap()).len(), 1);
+        assert!(generate_seeded(4, 1, 2, false, 7).is_ok());
+    }
+
+    #[test]
+    fn same_precedence_chains_associate_left() {
+        let holds = |puzzle, digits: [u8; 4]| {
+            let mapping = BTreeMap::from_iter("ABCD".chars().zip(digits));
+            evaluate(&parse_input(puzzle).unwrap(), &mapping).unwrap()
+        };
+        assert!(holds("A - B - C = D", [9, 3, 2, 4]));
+        assert!(!holds("A - B - C = D", [9, 3, 2, 8]));
+        assert!(holds("A / B / C = D", [8, 4, 2, 1]));
+        assert!(!holds("A / B / C = D", [8, 4, 2, 4]));
+    }
+
+    #[test]
+    fn multiplication_binds_tighter_than_addition() {
+        let mapping = BTreeMap::from([('A', 2), ('B', 3), ('C', 4)]);
+        let holds = |puzzle| evaluate(&parse_input(puzzle).unwrap(), &mapping).unwrap();
+        assert!(holds("A + B * C = 14"));
+        assert!(!holds("A + B * C = 20"));
+        assert!(holds("A * B + C = 10"));
+        assert!(!holds("A * B + C = 14"));
+    }
+
+    #[test]
+    fn units_column_prunes_before_the_leaf() {
+        let equation = parse_input("SEND + MORE = MONEY").unwrap();
+        let units = Search::new(&equation).units.unwrap();
+        let partial = BTreeMap::from([('D', 1), ('E', 2), ('Y', 4)]);
+        assert!(!units_consistent(&units, &partial, 10));
+        let partial = BTreeMap::from([('D', 7), ('E', 5), ('Y', 2)]);
+        assert!(units_consistent(&units, &partial, 10));
+
+        let mut equation = parse_input("TWO + TWO = FOUR").unwrap();
+        let units = Search::new(&equation).units.unwrap();
+        let mut nodes = |units| {
+            let search = Search {
+                units,
+                ..Search::new(&equation)
+            };
+            backtrack(&mut equation, &search, &mut |_| false).unwrap();
+            search.counters.nodes.load(Ordering::Relaxed)
+        };
+        let (checked, unchecked) = (nodes(Some(units)), nodes(None));
+        assert!(checked * 5 < unchecked);
+    }
+
+    #[test]
+    fn dividing_by_zero_rejects_the_candidate() {
+        let mut equation = parse_input("A / B = C").unwrap();
+        let mapping = BTreeMap::from([('A', 6), ('B', 0), ('C', 0)]);
+        assert_eq!(evaluate(&equation, &mapping), Ok(false));
+        equation.leading_zeros = true;
+        let solutions = solve_all(equation);
+        assert!(!solutions.is_empty());
+        assert!(solutions.iter().all(|solution| solution[&'B'] != 0));
+    }
+
+    #[test]
+    fn subtraction_can_go_negative() {
+        let mapping = BTreeMap::from([('A', 1), ('B', 3), ('C', 5), ('D', 3)]);
+        assert_eq!(
+            evaluate(&parse_input("A - B + C = D").unwrap(), &mapping),
+            Ok(true)
+        );
+        // The search starts at A = 1, below every B it can try
+        let solutions = solve_all(parse_input("A - B = C").unwrap());
+        assert_eq!(solutions.len(), 32);
+        assert!(solutions
+            .iter()
+            .all(|solution| solution[&'A'] == solution[&'B'] + solution[&'C']));
+    }
+
+    #[test]
+    fn overflowing_products_reject_the_candidate() {
+        let mapping = BTreeMap::from_iter("ABCDEFGHIJ".chars().zip((0..10).rev()));
+        let equation = parse_input("ABCDE * FGHIJ = JIHGFEDCBA").unwrap();
+        assert_eq!(evaluate(&equation, &mapping), Ok(false));
+        let equation = parse_input("ABCDEFGHIJ * ABCDEFGHIJ = ABCDEFGHIJ").unwrap();
+        assert_eq!(evaluate(&equation, &mapping), Ok(false));
+        let equation = parse_input("ABCDE * FGHIJ = JIHGFEDCBA").unwrap();
+        assert!(matches!(
+            solve_with_budget(equation, 100_000),
+            Ok(_) | Err(SolveError::BudgetExhausted)
+        ));
+    }
+
+    #[test]
+    fn tabs_and_newlines_separate_tokens() {
+        let equation = parse_input("SEND + MORE = MONEY").unwrap();
+        assert_eq!(parse_input("SEND\t+\nMORE = MONEY"), Ok(equation));
+    }
+
+    #[test]
+    fn negative_results_solve() {
+        let equation = parse_input("A - B = -C").unwrap();
+        assert_eq!(equation.to_string(), "A - B = -C");
+        let solutions = solve_all(equation);
+        assert_eq!(solutions.len(), 32);
+        assert!(solutions
+            .iter()
+            .all(|solution| solution[&'B'] == solution[&'A'] + solution[&'C']));
+        let equation = parse_input("A + B = +C").unwrap();
+        assert_eq!(solve_all(equation).len(), 32);
+    }
+
+    #[test]
+    fn quotes_and_separators_stay_reserved() {
+        let operator = BinaryOperator {
+            precedence: 1,
+            right_associative: false,
+            apply: i64::checked_add,
+        };
+        for symbol in [QUOTE, ';'] {
+            assert_eq!(
+                register_operator(symbol, operator),
+                Err(CryptarithmError::InvalidOperator(symbol))
+            );
+        }
+    }
+
+    #[test]
+    fn operators_register_only_once() {
+        let operator = |apply| BinaryOperator {
+            precedence: 1,
+            right_associative: false,
+            apply,
+        };
+        // Process-wide like every registration; no other test parses `¤`
+        assert_eq!(register_operator('¤', operator(i64::checked_add)), Ok(()));
+        assert_eq!(
+            register_operator('¤', operator(i64::checked_sub)),
+            Err(CryptarithmError::InvalidOperator('¤'))
+        );
+        let mapping = BTreeMap::from([('A', 2), ('B', 3)]);
+        assert!(evaluate(&parse_input("A ¤ B = 5").unwrap(), &mapping).unwrap());
+    }
+
+    #[test]
+    fn json_escapes_the_equation() {
+        let operator = |apply| BinaryOperator {
+            precedence: 1,
+            right_associative: false,
+            apply,
+        };
+        // The registry is process-wide, so these stay operators for the rest of the run;
+        // no other test parses `"` or `\`
+        register_operator('"', operator(i64::checked_add)).unwrap();
+        register_operator('\\', operator(i64::checked_sub)).unwrap();
+        let equation = parse_input("A \" B \\ C = D").unwrap();
+        let mapping = BTreeMap::from([('A', 1), ('B', 5), ('C', 2), ('D', 4)]);
+        let json = solution_to_json(&equation, Some(&mapping)).unwrap();
+        assert!(json.ends_with(r#""equation":"1 \" 5 \\ 2 = 4"}"#));
+        assert_eq!(json_escape("\t"), r"\u0009");
+    }
+
+    #[test]
+    fn solvers_skip_a_result_of_the_wrong_width() {
+        let equation = parse_input("ABCD + EFGH = IJ").unwrap();
+        let search = Search::new(&equation);
+        assert_eq!(first_solution(equation.clone(), &search), None);
+        assert_eq!(search.counters.nodes.load(Ordering::Relaxed), 0);
+        assert_eq!(solve_deterministic(equation.clone()), None);
+        assert!(solve_all(equation).is_empty());
+    }
+
+    #[test]
+    fn greek_and_cyrillic_letters_solve() {
+        let equation = parse_input("ΑΒ + ΓΔ = ΕΖ").unwrap();
+        assert_eq!(equation.unique_chars, BTreeSet::from_iter("ΑΒΓΔΕΖ".chars()));
+        assert_eq!(equation.special_chars, BTreeSet::from(['Α', 'Γ', 'Ε']));
+        let solution = solve_deterministic(equation.clone()).unwrap();
+        assert!(evaluate(&equation, &solution).unwrap());
+        assert!(solution[&'Α'] != 0 && solution[&'Γ'] != 0 && solution[&'Ε'] != 0);
+
+        let equation = parse_input("АБВГД + ЕЖЗИЙ = КАБ").unwrap();
+        assert_eq!(
+            solve_with_budget(equation, 1),
+            Err(SolveError::TooManyLetters {
+                letters: 11,
+                base: 10
+            })
+        );
+    }
+
+    #[test]
+    fn digit_tables_handle_collisions_and_gaps() {
+        let colliding = BTreeMap::from([('A', 1), ('B', 1)]);
+        assert_eq!(invert_mapping(&colliding), BTreeMap::from([(1, 'A')]));
+
+        let equation = parse_input("A + B = C").unwrap().in_base(4).unwrap();
+        let partial = BTreeMap::from([('A', 1), ('C', 3)]);
+        assert_eq!(
+            format_digit_table(&equation, &partial),
+            "0: -\n1: A\n2: -\n3: C"
+        );
+        assert_eq!(
+            format_digit_table(&equation, &colliding),
+            "0: -\n1: AB\n2: -\n3: -"
+        );
+    }
+
+    #[test]
+    fn ignoring_case_keeps_every_letter() {
+        let equation = parse_input_ignore_case("straße + a = b").unwrap();
+        assert_eq!(equation.to_string(), "STRAßE + A = B");
+        assert_eq!(
+            parse_input_ignore_case("straße + a = b $"),
+            Err(CryptarithmError::InvalidToken {
+                ch: '$',
+                position: 15
+            })
+        );
+    }
+
+    #[cfg(feature = "threads")]
+    #[test]
+    fn only_exhausted_branches_count_as_done() {
+        let (solution, stats) =
+            solve_with_stats(parse_input("SEND + MORE = MONEY").unwrap()).unwrap();
+        assert!(solution.is_some());
+        assert!(stats.branches_done < stats.branches);
+        let (solution, stats) = solve_with_stats(parse_input("AB + AB = AB").unwrap()).unwrap();
+        assert!(solution.is_none());
+        assert_eq!(stats.branches_done, stats.branches);
+    }
+
+    #[test]
+    fn json_reports_the_remainder() {
+        let equation = parse_input("AB / C = D r E").unwrap();
+        let mapping = BTreeMap::from([('A', 1), ('B', 7), ('C', 5), ('D', 3), ('E', 2)]);
+        assert_eq!(
+            solution_to_json(&equation, Some(&mapping)).unwrap(),
+            r#"{"solved":true,"mapping":{"A":1,"B":7,"C":5,"D":3,"E":2},"operands":[17,5],"result":3,"remainder":2,"equation":"17 / 5 = 3 r 2"}"#
+        );
+    }
+
+    #[test]
+    fn result_width_allows_leading_zeros() {
+        let mut equation = parse_input("ABC + D = EF").unwrap();
+        equation.leading_zeros = true;
+        assert_eq!(equation.validate(), Ok(()));
+        assert!(!solve_all(equation).is_empty());
+    }
 }
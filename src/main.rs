@@ -0,0 +1,118 @@
+use cryptarithm_solver::{
+    format_solution, parse_input, solve_iter_with_timeout, solve_with_timeout,
+};
+use std::io::Read;
+use std::process::ExitCode;
+use std::time::Duration;
+
+const USAGE: &str = "usage: cryptarithm [--timeout SECS] [--all] [--base N] [PUZZLE...]
+Reads the puzzle from stdin when none is given, e.g. `cryptarithm SEND + MORE = MONEY`.";
+
+struct Args {
+    puzzle: String,
+    timeout: Option<Duration>,
+    all: bool,
+    base: Option<u8>,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args {
+        puzzle: String::new(),
+        timeout: None,
+        all: false,
+        base: None,
+    };
+    let mut words = vec![];
+    let mut argv = std::env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        match arg.as_str() {
+            "--timeout" => {
+                let secs = argv.next().ok_or("--timeout needs a value")?;
+                let secs: f64 = secs
+                    .parse()
+                    .map_err(|_| format!("invalid timeout: {}", secs))?;
+                args.timeout =
+                    Some(Duration::try_from_secs_f64(secs).map_err(|err| err.to_string())?);
+            }
+            "--base" => {
+                let base = argv.next().ok_or("--base needs a value")?;
+                args.base = Some(
+                    base.parse()
+                        .map_err(|_| format!("invalid base: {}", base))?,
+                );
+            }
+            "--all" => args.all = true,
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            _ => words.push(arg),
+        }
+    }
+    args.puzzle = if words.is_empty() {
+        let mut input = String::new();
+        std::io::stdin()
+            .read_to_string(&mut input)
+            .map_err(|err| err.to_string())?;
+        input
+    } else {
+        words.join(" ")
+    };
+    Ok(args)
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(message) => {
+            eprintln!("{}", message);
+            return ExitCode::from(2);
+        }
+    };
+    let equation = match parse_input(args.puzzle.trim()) {
+        Ok(equation) => match args.base {
+            Some(base) => equation.in_base(base),
+            None => Ok(equation),
+        },
+        Err(err) => Err(err),
+    };
+    let equation = match equation {
+        Ok(equation) => equation,
+        Err(err) => {
+            eprintln!("{}", err);
+            return ExitCode::from(2);
+        }
+    };
+
+    let solutions = if args.all {
+        // Listing every solution has no deadline unless one was asked for
+        let timeout = args.timeout.unwrap_or(Duration::MAX);
+        match solve_iter_with_timeout(equation.clone(), timeout).collect::<Result<Vec<_>, _>>() {
+            Ok(mut solutions) => {
+                solutions.sort_unstable();
+                solutions
+            }
+            Err(err) => {
+                eprintln!("{}", err);
+                return ExitCode::FAILURE;
+            }
+        }
+    } else {
+        let timeout = args.timeout.unwrap_or(Duration::from_secs(7));
+        match solve_with_timeout(equation.clone(), timeout) {
+            Ok(solution) => solution.into_iter().collect(),
+            Err(err) => {
+                eprintln!("{}", err);
+                return ExitCode::FAILURE;
+            }
+        }
+    };
+    if solutions.is_empty() {
+        println!("No solution");
+        return ExitCode::FAILURE;
+    }
+    for solution in solutions {
+        match format_solution(&equation, &solution) {
+            Ok(line) => println!("{}", line),
+            Err(err) => println!("{:?}: {}", solution, err),
+        }
+    }
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,63 @@
+//! Python bindings, built with the `python` feature and imported as `cryptarithm_solver`.
+//!
+//! The crate itself only builds an rlib; run
+//! `cargo rustc --lib --release --features python --crate-type cdylib` and rename the
+//! resulting `libcryptarithm_solver.so` to `cryptarithm_solver.so` to import it.
+
+use crate::{CryptarithmError, SolveError};
+use pyo3::exceptions::{PyTimeoutError, PyValueError};
+use pyo3::prelude::*;
+use std::collections::BTreeMap;
+
+/// A puzzle from `parse_input`.
+#[pyclass(name = "Equation", frozen)]
+struct PyEquation(crate::Equation);
+
+#[pymethods]
+impl PyEquation {
+    fn __str__(&self) -> String {
+        self.0.to_string()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Equation('{}')", self.0)
+    }
+}
+
+fn value_error(error: CryptarithmError) -> PyErr {
+    PyValueError::new_err(error.to_string())
+}
+
+#[pyfunction]
+fn parse_input(expression: &str) -> PyResult<PyEquation> {
+    crate::parse_input(expression)
+        .map(PyEquation)
+        .map_err(value_error)
+}
+
+/// A dict of letter to digit, or `None` when there is no solution. The GIL is released
+/// while searching; running out of time raises `TimeoutError`.
+#[pyfunction]
+fn solve(py: Python<'_>, equation: &PyEquation) -> PyResult<Option<BTreeMap<char, u8>>> {
+    let equation = equation.0.clone();
+    py.detach(move || crate::solve(equation))
+        .map_err(|error| match error {
+            SolveError::Timeout => PyTimeoutError::new_err(error.to_string()),
+            _ => PyValueError::new_err(error.to_string()),
+        })
+}
+
+/// The puzzle with every letter replaced by its digit in `mapping`.
+#[pyfunction]
+fn format_solution(equation: &PyEquation, mapping: BTreeMap<char, u8>) -> PyResult<String> {
+    crate::format_solution(&equation.0, &mapping).map_err(value_error)
+}
+
+#[pymodule]
+fn cryptarithm_solver(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<PyEquation>()?;
+    module.add_function(wrap_pyfunction!(parse_input, module)?)?;
+    module.add_function(wrap_pyfunction!(solve, module)?)?;
+    module.add_function(wrap_pyfunction!(format_solution, module)?)?;
+    Ok(())
+}